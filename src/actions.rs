@@ -1,25 +1,26 @@
-use crate::crd::HealthCheck;
+use crate::crd::{HealthCheck, NodeBalancerScope, PortMapping, PortRef, ProbeTarget, Weighting};
 use k8s_openapi::api::core::v1::NodeAddress;
 use kube::api::{ListParams, Patch, PatchParams};
-use kube::{Api, Client, Error};
+use kube::{Api, Client, Resource, ResourceExt};
+use kube::runtime::events::{Event, EventType, Recorder};
 use serde_json::{from_value, json, Value};
-use std::time::Duration;
-use k8s_openapi::api::core::v1::{Node, Pod};
-use port_check::*;                                                                                                                                                                                 
+use std::time::{Duration, Instant};
+use k8s_openapi::api::core::v1::{Node, ObjectReference, Pod, Service, Taint};
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
 //use std::net::*;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::sync::{LazyLock, Mutex};
+use std::net::{IpAddr, SocketAddr};
 use serde::{Serialize, Deserialize};
 //use serde_json_path::JsonPath;
 //use kube::api::ObjectMeta;
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time, ManagedFieldsEntry, OwnerReference};
-use k8s_openapi::api::core::v1::{ PodSpec, PodStatus };
 use tokio_postgres::Row;
 use crate::hcapi;
-use crate::database::{
-    get_by_node_ip_nbcfg,
-    update_state,
-    get_db_state,
-};
+use crate::database::LocalNodeBalancerListObject;
+use crate::store::{store, AuditRecord, NbNode, NbScope, StateRow, StateUpdate};
+use crate::Error;
 
 
 //mod database;
@@ -35,51 +36,368 @@ struct NodePatch {
     metadata: NodeMetadataPatch,
 }
 
-fn get_private_address(node: &Node) -> Option<String> {
+// All InternalIP addresses of the node, one per family on dual-stack nodes.
+pub fn get_private_addresses(node: &Node) -> Vec<IpAddr> {
+    let mut ips = Vec::new();
     if let Some(addresses) = node.status.as_ref().and_then(|s| s.addresses.as_ref()) {
         for address in addresses {
-            if address.type_ == "InternalIP" {
-                return Some(address.address.clone());
+            if address.type_ != "InternalIP" {
+                continue;
+            }
+            match address.address.parse() {
+                Ok(ip) => ips.push(ip),
+                Err(_) => println!("Ignoring invalid InternalIP {:?} on node {:?}", address.address, node.metadata.name),
             }
         }
     }
+    ips
+}
+
+pub const OVERRIDE_ANNOTATION: &str = "hc.example.com/override";
+pub const OVERRIDE_EXPIRES_ANNOTATION: &str = "hc.example.com/override-expires";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NodeOverride {
+    Accept,
+    Drain,
+    Ignore,
+}
+
+// Manual override set on the node by an operator, e.g. during an incident.
+// An optional RFC3339 expiry turns the override off once it has passed.
+pub fn get_override(node: &Node) -> Option<NodeOverride> {
+    let annotations = node.metadata.annotations.as_ref()?;
+    let value = annotations.get(OVERRIDE_ANNOTATION)?;
+    let node_override = match value.trim() {
+        "accept" => NodeOverride::Accept,
+        "drain" => NodeOverride::Drain,
+        "ignore" => NodeOverride::Ignore,
+        other => {
+            println!("Unknown value {:?} for {}, ignoring override", other, OVERRIDE_ANNOTATION);
+            return None;
+        }
+    };
+    if let Some(expires) = annotations.get(OVERRIDE_EXPIRES_ANNOTATION) {
+        match chrono::DateTime::parse_from_rfc3339(expires.trim()) {
+            Ok(expiry) if expiry <= chrono::Utc::now() => return None,
+            Ok(_) => (),
+            Err(e) => {
+                println!("Invalid {} {:?}: {}, ignoring override", OVERRIDE_EXPIRES_ANNOTATION, expires, e);
+                return None;
+            }
+        }
+    }
+    Some(node_override)
+}
+
+pub const DELETE_MACHINE_ANNOTATION: &str = "cluster.x-k8s.io/delete-machine";
+
+// Reason to drain a node ahead of its probes failing: it is cordoned or
+// Cluster API has marked its machine for deletion.
+pub fn get_drain_trigger(node: &Node) -> Option<&'static str> {
+    let annotations = node.metadata.annotations.as_ref();
+    if annotations.is_some_and(|a| a.contains_key(DELETE_MACHINE_ANNOTATION)) {
+        return Some("MachineDeletion");
+    }
+    if node.spec.as_ref().and_then(|s| s.unschedulable) == Some(true) {
+        return Some("Cordoned");
+    }
     None
 }
 
-pub async fn get_hc_pod_ip(client: Client, target_node_name: &String, ns: &str, hcport: i32) -> Vec<String> {
+pub async fn update_hc_status(client: Client, hc_name: &str, node_name: &str, mode: &str, reason: &str) {
+    let api: Api<HealthCheck> = Api::namespaced(client, "default");
+    let patch = json!({
+        "status": {
+            "nodes": {
+                node_name: {
+                    "mode": mode,
+                    "reason": reason,
+                    "last_transition": chrono::Utc::now().to_rfc3339(),
+                }
+            }
+        }
+    });
+    if let Err(e) = api.patch_status(hc_name, &PatchParams::default(), &Patch::Merge(&patch)).await {
+        println!("Failed to update status of HealthCheck {}: {:?}", hc_name, e);
+    }
+}
+
+pub const READINESS_GATE: &str = "hc.example.com/in-load-balancer";
+
+// Set the in-load-balancer condition on a pod that lists it in its
+// readinessGates. It follows the NodeBalancer nodes behind the pod rather
+// than the pod's own probe: it turns False as soon as the combined mode of
+// any of them, over every HealthCheck covering it, is not accept, and only
+// turns True once that combined mode is accept and the Linode API reports
+// every one of them in accept, so rollouts wait for real admission.
+pub async fn sync_readiness_gate(client: Client, ns: &str, name: &str, port: i32, target: &ProbeAddress, scope: &NbScope, clustername: &str) {
+    let Some(current) = target.in_load_balancer else { return };
+    let nb_nodes = match get_nb_nodes(client.clone(), name, port, target.ip, scope).await {
+        Ok(nb_nodes) => nb_nodes,
+        Err(e) => {
+            println!("Could not find NodeBalancer nodes for pod {}: {:?}", target.ip, e);
+            return;
+        }
+    };
+    let mut in_load_balancer = !nb_nodes.is_empty();
+    for nb_node in &nb_nodes {
+        match store().get_nb_node_states(nb_node.node_id, clustername, false).await {
+            Ok(rows) => in_load_balancer &= combined_mode(&rows).as_deref() == Some("accept"),
+            Err(e) => {
+                println!("Could not read state of NodeBalancer node {}: {:?}", nb_node.node_id, e);
+                return;
+            }
+        }
+    }
+    if in_load_balancer && !current {
+        in_load_balancer = nodebalancers_accepting(&nb_nodes).await;
+    }
+    if in_load_balancer == current {
+        return;
+    }
+    let api: Api<Pod> = Api::namespaced(client, ns);
+    let patch = json!({
+        "status": {
+            "conditions": [{
+                "type": READINESS_GATE,
+                "status": if in_load_balancer { "True" } else { "False" },
+                "reason": if in_load_balancer { "NodeBalancerAccepting" } else { "NodeBalancerNotAccepting" },
+                "lastTransitionTime": chrono::Utc::now().to_rfc3339(),
+            }]
+        }
+    });
+    match api.patch_status(&target.pod_name, &PatchParams::default(), &Patch::Strategic(&patch)).await {
+        Ok(_) => println!("Pod {} {} set to {}", target.pod_name, READINESS_GATE, in_load_balancer),
+        Err(e) => println!("Failed to set {} on pod {}: {:?}", READINESS_GATE, target.pod_name, e),
+    }
+}
+
+async fn nodebalancers_accepting(nb_nodes: &[NbNode]) -> bool {
+    for nb_node in nb_nodes {
+        match hcapi::get_node(&nb_node.nodebalancer_id, &nb_node.config_id, &nb_node.node_id).await {
+            Ok(node) if node.mode == "accept" => (),
+            Ok(_) => return false,
+            Err(e) => {
+                println!("Could not read NodeBalancer {} node {}: {:?}", nb_node.nodebalancer_id, nb_node.node_id, e);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+// Prefix of the per-HealthCheck verdict label and taint on nodes.
+const VERDICT_PREFIX: &str = "hc.example.com/";
+
+// Label (and optionally taint) the node with this HealthCheck's verdict so
+// other controllers and the scheduler see what the NodeBalancer sees.
+pub async fn set_node_verdict(client: Client, name: &str, hc_name: &str, healthy: bool, taint_unhealthy: bool) {
+    let api: Api<Node> = Api::all(client);
+    let node = match api.get(name).await {
+        Ok(node) => node,
+        Err(e) => {
+            println!("Failed to get node {} for verdict label: {:?}", name, e);
+            return;
+        }
+    };
+    let key = format!("{}{}", VERDICT_PREFIX, hc_name);
+    let value = if healthy { "healthy" } else { "unhealthy" };
+
+    if node.labels().get(&key).map(String::as_str) != Some(value) {
+        let patch = NodePatch {
+            metadata: NodeMetadataPatch {
+                labels: BTreeMap::from([(key.clone(), value.to_string())]),
+            },
+        };
+        if let Err(e) = api.patch(name, &PatchParams::default(), &Patch::Merge(&patch)).await {
+            println!("Failed to label node {}: {:?}", name, e);
+        }
+    }
+
+    let taints = node.spec.as_ref().and_then(|s| s.taints.clone()).unwrap_or_default();
+    let tainted = taints.iter().any(|t| t.key == key);
+    let want_taint = taint_unhealthy && !healthy;
+    if tainted != want_taint {
+        let mut taints: Vec<Taint> = taints.into_iter().filter(|t| t.key != key).collect();
+        if want_taint {
+            taints.push(Taint {
+                key: key.clone(),
+                value: Some("unhealthy".to_string()),
+                effect: "NoSchedule".to_string(),
+                time_added: None,
+            });
+        }
+        // Taints are replaced as a whole list, so guard against concurrent writers.
+        let patch = json!({
+            "metadata": { "resourceVersion": node.resource_version() },
+            "spec": { "taints": taints },
+        });
+        if let Err(e) = api.patch(name, &PatchParams::default(), &Patch::Merge(&patch)).await {
+            println!("Failed to update taints on node {}: {:?}", name, e);
+        }
+    }
+}
+
+// Verdict labels and taints on the node whose HealthCheck no longer exists.
+fn stale_verdict_keys(node: &Node, healthchecks: &BTreeSet<String>) -> BTreeSet<String> {
+    let labels = node.labels().iter().filter(|(_, value)| matches!(value.as_str(), "healthy" | "unhealthy")).map(|(key, _)| key);
+    let taints = node.spec.as_ref().and_then(|s| s.taints.as_ref()).into_iter().flatten();
+    let taints = taints.filter(|t| t.effect == "NoSchedule" && t.value.as_deref() == Some("unhealthy")).map(|t| &t.key);
+    labels
+        .chain(taints)
+        .filter(|key| key.strip_prefix(VERDICT_PREFIX).is_some_and(|hc_name| !healthchecks.contains(hc_name)))
+        .cloned()
+        .collect()
+}
+
+async fn clear_node_verdicts(client: Client, node: &Node, keys: &BTreeSet<String>) {
+    let api: Api<Node> = Api::all(client);
+    let name = node.name_any();
+    let labels: serde_json::Map<String, Value> = keys.iter().map(|key| (key.clone(), Value::Null)).collect();
+    let mut patch = json!({ "metadata": { "labels": labels } });
+    let taints = node.spec.as_ref().and_then(|s| s.taints.clone()).unwrap_or_default();
+    if taints.iter().any(|t| keys.contains(&t.key)) {
+        // Taints are replaced as a whole list, so guard against concurrent writers.
+        let taints: Vec<Taint> = taints.into_iter().filter(|t| !keys.contains(&t.key)).collect();
+        patch["metadata"]["resourceVersion"] = json!(node.resource_version());
+        patch["spec"] = json!({ "taints": taints });
+    }
+    match api.patch(&name, &PatchParams::default(), &Patch::Merge(&patch)).await {
+        Ok(_) => {
+            println!("State GC: removed verdicts {:?} from node {} - HealthCheckDeleted", keys, name);
+            crate::metrics::inc("hc_operator_state_gc_total", &[("reason", "HealthCheckDeleted")]);
+        }
+        Err(e) => println!("State GC could not remove verdicts from node {}: {:?}", name, e),
+    }
+}
+
+pub async fn publish_node_event(recorder: &Recorder, node: &Node, mode: &str, reason: &str, note: String, dry_run: bool) {
+    let type_ = if mode == "accept" { EventType::Normal } else { EventType::Warning };
+    let event = Event {
+        type_,
+        reason: reason.to_string(),
+        note: Some(if dry_run { format!("[dry run] {}", note) } else { note }),
+        action: format!("{}NodeBalancer{}", if dry_run { "DryRun" } else { "" }, if mode == "accept" { "Accept" } else { "Drain" }),
+        secondary: None,
+    };
+    if let Err(e) = recorder.publish(&event, &node.object_ref(&())).await {
+        println!("Failed to publish event for node {:?}: {:?}", node.metadata.name, e);
+    }
+}
 
-    #[derive(Serialize, Deserialize, Debug)]
-    struct ContainerPort {
-        container_port: u32,
+// A HealthCheck that can't be applied, e.g. an invalid maintenance window or
+// a probe port the target doesn't have.
+pub async fn publish_healthcheck_event(recorder: &Recorder, hc: &ObjectReference, note: String) {
+    let event = Event {
+        type_: EventType::Warning,
+        reason: "InvalidHealthCheck".to_string(),
+        note: Some(note),
+        action: "Reconcile".to_string(),
+        secondary: None,
+    };
+    if let Err(e) = recorder.publish(&event, hc).await {
+        println!("Failed to publish event for HealthCheck {:?}: {:?}", hc.name, e);
     }
+}
 
-    #[derive(Serialize, Deserialize, Debug)]
-    struct ZeroPods {
-        //Pod: ObjectMeta,
+// An address to probe. pod_name and pod_uid are empty for node addresses,
+// and terminating is set once the pod has a deletionTimestamp. not_ready is
+// why the kubelet doesn't consider the pod ready, if it doesn't.
+// in_load_balancer is the pod's READINESS_GATE condition, None if the pod
+// doesn't list the gate. probe_port is the Service's resolved targetPort or,
+// for node addresses, the NodeBalancer's backend port. container_ports are
+// the pod's named container ports.
+pub struct ProbeAddress {
+    pub ip: IpAddr,
+    pub probe_port: Option<i32>,
+    pub container_ports: BTreeMap<String, i32>,
+    pub pod_name: String,
+    pub pod_uid: String,
+    pub in_load_balancer: Option<bool>,
+    pub terminating: bool,
+    pub not_ready: Option<String>,
+}
+
+impl ProbeAddress {
+    pub fn node(ip: IpAddr, probe_port: Option<i32>) -> Self {
+        ProbeAddress {
+            ip,
+            probe_port,
+            container_ports: Default::default(),
+            pod_name: String::new(),
+            pod_uid: String::new(),
+            in_load_balancer: None,
+            terminating: false,
+            not_ready: None,
+        }
+    }
+}
+
+// The node's own addresses for target nodeIP. The NodeBalancer sends traffic
+// for port to a backend port on the node, e.g. a NodePort, so each address is
+// probed on every backend port its NodeBalancer nodes use. An address without
+// NodeBalancer nodes is probed on port.
+pub async fn node_probe_addresses(node: &Node, port: i32, scope: &NbScope) -> Result<Vec<ProbeAddress>, Error> {
+    let mut targets = Vec::new();
+    for ip in get_private_addresses(node) {
+        let backend_ports: BTreeSet<i32> = store()
+            .get_by_node_ip_nbcfg(ip, port, scope)
+            .await?
+            .iter()
+            .filter_map(|nb_node| nb_node.address.parse::<SocketAddr>().ok())
+            .map(|address| address.port() as i32)
+            .collect();
+        if backend_ports.is_empty() {
+            targets.push(ProbeAddress::node(ip, None));
+        }
+        targets.extend(backend_ports.into_iter().map(|backend_port| ProbeAddress::node(ip, Some(backend_port))));
     }
+    Ok(targets)
+}
+
+// A container that restarted more recently than this counts as not ready,
+// even if its readiness probe already passes again.
+const RECENT_RESTART_SECS: i64 = 60;
 
-    #[derive(Serialize, Deserialize, Debug)]
-    struct Pods {
-        apiVersion: String,
-        kind: String,
-        metadata: ObjectMeta,
-        spec: PodSpec,
-        status: PodStatus,
-        //Pod: MetaData,
+fn readiness_gate_status(pod: &Pod) -> Option<bool> {
+    let gates = pod.spec.as_ref()?.readiness_gates.as_ref()?;
+    if !gates.iter().any(|gate| gate.condition_type == READINESS_GATE) {
+        return None;
     }
+    let conditions = pod.status.as_ref().and_then(|s| s.conditions.as_ref());
+    Some(conditions.into_iter().flatten().any(|c| c.type_ == READINESS_GATE && c.status == "True"))
+}
 
-    #[derive(Serialize, Deserialize, Debug)]
-    struct MetaData {
-        metadata: ObjectMeta,
-        spec: PodSpec,
-        status: PodStatus,
+pub fn pod_not_ready(pod: &Pod) -> Option<String> {
+    let Some(status) = &pod.status else { return Some("pod has no status".to_string()) };
+    for container in status.container_statuses.iter().flatten() {
+        let waiting = container.state.as_ref().and_then(|s| s.waiting.as_ref());
+        if let Some(reason) = waiting.and_then(|w| w.reason.as_deref()).filter(|r| *r == "CrashLoopBackOff") {
+            return Some(format!("container {} is in {}", container.name, reason));
+        }
+        let finished = container.last_state.as_ref().and_then(|s| s.terminated.as_ref()).and_then(|t| t.finished_at.as_ref());
+        if let Some(finished) = finished {
+            let age = (chrono::Utc::now() - finished.0).num_seconds();
+            if age < RECENT_RESTART_SECS {
+                return Some(format!("container {} restarted {}s ago ({} restarts)", container.name, age, container.restart_count));
+            }
+        }
+    }
+    // Ready waits for READINESS_GATE, which waits for this verdict, so pods
+    // with the gate are judged by their containers alone.
+    let condition = if readiness_gate_status(pod).is_some() { "ContainersReady" } else { "Ready" };
+    let ready = status.conditions.iter().flatten().any(|c| c.type_ == condition && c.status == "True");
+    if !ready {
+        return Some(format!("pod {} condition is not True", condition));
     }
+    None
+}
 
-    let mut ip_vector: Vec<String> = Vec::new();
-    let mut t: i32 = 0;
-    //let mut hcpod_ip: String = "0.0.0.0".to_string();
+pub async fn get_hc_pod_ip(client: Client, target_node_name: &String, ns: &str) -> Result<Vec<ProbeAddress>, Error> {
+    let mut ip_vector: Vec<ProbeAddress> = Vec::new();
     let pods: Api<Pod> = Api::namespaced(client, ns);
-    let pod_list = pods.list(&ListParams::default()).await.unwrap();
+    let pod_list = pods.list(&ListParams::default()).await?;
     let filtered_pods: Vec<Pod> = pod_list
         .items
         .into_iter()
@@ -90,128 +408,1081 @@ pub async fn get_hc_pod_ip(client: Client, target_node_name: &String, ns: &str,
                 false
             }
         })
-        .filter(|p| {
-            if !p.metadata.name.as_deref().unwrap().contains("node-health-check-operator") {
-                true
-            } else {
-                false
+        .filter(|p| !p.name_any().contains("node-health-check-operator"))
+        .collect();
+    for f in filtered_pods {
+        for ip in pod_addresses(&f) {
+            if !ip_vector.iter().any(|seen| seen.ip == ip) {
+                ip_vector.push(pod_probe_address(&f, ip));
             }
-        })
+        }
+    }
+    Ok(ip_vector)
+}
+
+fn pod_probe_address(pod: &Pod, ip: IpAddr) -> ProbeAddress {
+    let containers = pod.spec.iter().flat_map(|spec| spec.containers.iter());
+    let container_ports = containers
+        .flat_map(|container| container.ports.iter().flatten())
+        .filter_map(|port| Some((port.name.clone()?, port.container_port)))
         .collect();
-    if filtered_pods.len() == 0 {
-        let filtered_pods_results = serde_json::to_string(&filtered_pods).unwrap();
-        let my_struct: ZeroPods = serde_json::from_str(&filtered_pods_results).expect("ZeroPods Failed");
-        ip_vector.push("0.0.0.0".to_string());
-        
+    ProbeAddress {
+        ip,
+        probe_port: None,
+        container_ports,
+        pod_name: pod.name_any(),
+        pod_uid: pod.uid().unwrap_or_default(),
+        in_load_balancer: readiness_gate_status(pod),
+        terminating: pod.metadata.deletion_timestamp.is_some(),
+        not_ready: pod_not_ready(pod),
     }
-    if filtered_pods.len() > 0 {
-        for f in filtered_pods {
-            let filtered_pods_results = serde_json::to_value(f).unwrap();
-            let my_struct: Pods = serde_json::from_value(filtered_pods_results).expect("Pod Failed");
-            //This is where the bug is when new pods start. Panic sometimes occurs
-            let result = my_struct.status.pod_ip.clone();
-            if result != None {
-                ip_vector.push(result.clone().expect("IP string conversion failed").to_string());
+}
+
+// Endpoints of a Service on the node, from its EndpointSlices. The Service
+// port is the one matching `port`, or its only port. Each endpoint is probed
+// on that port's targetPort as resolved in its slice, so named ports follow
+// the pods' container ports.
+pub async fn get_service_targets(client: Client, target_node_name: &str, ns: &str, service_name: &str, port: i32) -> Result<Vec<ProbeAddress>, Error> {
+    let service = Api::<Service>::namespaced(client.clone(), ns).get(service_name).await?;
+    let service_ports = service.spec.and_then(|s| s.ports).unwrap_or_default();
+    let service_port = match service_ports.iter().find(|p| p.port == port) {
+        Some(service_port) => service_port,
+        None if service_ports.len() == 1 => &service_ports[0],
+        None => return Err(Error::UserInputError(format!("Service {}/{} has no port {}", ns, service_name, port))),
+    };
+    let port_name = service_port.name.as_deref().unwrap_or_default();
+    let fallback_port = match &service_port.target_port {
+        Some(IntOrString::Int(target_port)) => Some(*target_port),
+        Some(IntOrString::String(_)) => None,
+        None => Some(service_port.port),
+    };
+
+    let lp = ListParams::default().labels(&format!("kubernetes.io/service-name={}", service_name));
+    let slices = Api::<EndpointSlice>::namespaced(client.clone(), ns).list(&lp).await?;
+    let lp = ListParams::default().fields(&format!("spec.nodeName={}", target_node_name));
+    let pods: HashMap<String, Pod> = Api::<Pod>::namespaced(client, ns)
+        .list(&lp)
+        .await?
+        .items
+        .into_iter()
+        .filter_map(|pod| Some((pod.uid()?, pod)))
+        .collect();
+
+    let mut targets: Vec<ProbeAddress> = Vec::new();
+    for slice in slices {
+        let slice_port = slice.ports.iter().flatten().find(|p| p.name.as_deref().unwrap_or_default() == port_name).and_then(|p| p.port);
+        let Some(probe_port) = slice_port.or(fallback_port) else {
+            println!("EndpointSlice {} has no port {:?} for Service {}/{}", slice.name_any(), port_name, ns, service_name);
+            continue;
+        };
+        for endpoint in slice.endpoints {
+            if endpoint.node_name.as_deref() != Some(target_node_name) {
+                continue;
+            }
+            let target_ref = endpoint.target_ref.unwrap_or_default();
+            let pod = target_ref.uid.as_ref().and_then(|uid| pods.get(uid));
+            let conditions = endpoint.conditions.unwrap_or_default();
+            for address in endpoint.addresses {
+                let Ok(ip) = address.parse::<IpAddr>() else {
+                    println!("Ignoring invalid endpoint address {:?} of Service {}/{}", address, ns, service_name);
+                    continue;
+                };
+                if targets.iter().any(|seen| seen.ip == ip) {
+                    continue;
+                }
+                // Endpoints without a pod we can read are judged by the slice's conditions.
+                let mut target = match pod {
+                    Some(pod) => pod_probe_address(pod, ip),
+                    None => ProbeAddress {
+                        ip,
+                        probe_port: None,
+                        container_ports: BTreeMap::new(),
+                        pod_name: target_ref.name.clone().unwrap_or_default(),
+                        pod_uid: target_ref.uid.clone().unwrap_or_default(),
+                        in_load_balancer: None,
+                        terminating: conditions.terminating == Some(true),
+                        not_ready: (conditions.ready == Some(false)).then(|| "endpoint is not ready".to_string()),
+                    },
+                };
+                target.probe_port = Some(probe_port);
+                targets.push(target);
             }
-                //Ok(val) if val == s.unwrap() => ip_vector.push(s.to_string()),
-                //Err(val) if val == e.unwrap() => ip_vector.push("0.0.0.0".to_string()),
+        }
+    }
+    Ok(targets)
+}
 
+// Pods that were only just scheduled have no IP yet. Dual-stack pods list
+// one address per family in podIPs.
+fn pod_addresses(pod: &Pod) -> Vec<IpAddr> {
+    let Some(status) = &pod.status else { return Vec::new() };
+    let pod_ips: Vec<String> = match &status.pod_ips {
+        Some(pod_ips) if !pod_ips.is_empty() => pod_ips.iter().map(|p| p.ip.clone()).collect(),
+        _ => status.pod_ip.clone().into_iter().collect(),
+    };
+    let mut addresses = Vec::new();
+    for pod_ip in pod_ips {
+        match pod_ip.parse() {
+            Ok(ip) => addresses.push(ip),
+            Err(_) => println!("Ignoring invalid pod IP {:?} on pod {}", pod_ip, pod.name_any()),
         }
     }
-    return ip_vector
+    addresses
 }
 
-pub async fn check_port(ip_address: String, port_number: i32, check_timeout: u64) -> bool {                                                                                                                                        
-     let addr = format!("{}:{}", ip_address.to_string(), port_number);                                                                                                                              
-     is_port_reachable_with_timeout(addr, Duration::from_secs(check_timeout))                                                                                                                                                    
+// The port to probe a target on for one mapping: the mapping's probe_port,
+// by number or container port name, else the Service's targetPort, else the
+// NodeBalancer port.
+pub fn probe_port(mapping: &PortMapping, target: &ProbeAddress) -> Result<i32, Error> {
+    match &mapping.probe_port {
+        Some(port) => resolve_port(port, target),
+        None => Ok(target.probe_port.unwrap_or(mapping.nodebalancer_port)),
+    }
+}
 
-     //true
-     
- }
+pub fn resolve_port(port: &PortRef, target: &ProbeAddress) -> Result<i32, Error> {
+    match port {
+        PortRef::Number(port) => Ok(*port),
+        PortRef::Name(name) => target
+            .container_ports
+            .get(name)
+            .copied()
+            .ok_or(Error::UserInputError(format!("{} has no container port named {}", if target.pod_name.is_empty() { target.ip.to_string() } else { target.pod_name.clone() }, name))),
+    }
+}
 
-pub async fn check_if_seen_before(client: Client, name: &str) -> bool {
-    let api: Api<Node> = Api::all(client);
-    let mut node = api.get(&name).await.unwrap();
-    let annotations: Option<&BTreeMap<String, String>> = node.metadata.annotations.as_ref();
-    let mut result = false;
-    if let Some(annotations) = annotations {
-        let annotation_key = "";
-        if let Some(annotation_value) = annotations.get(annotation_key) {
-            result = true;
-        } else {
-            result = false;
+pub async fn check_port(ip_address: IpAddr, port_number: i32, check_timeout: u64) -> Result<bool, Error> {
+    let port = u16::try_from(port_number).map_err(|_| Error::ProbeError(format!("invalid probe port {}", port_number)))?;
+    let addr = SocketAddr::new(ip_address, port);
+    let connect = tokio::time::timeout(Duration::from_secs(check_timeout), tokio::net::TcpStream::connect(addr)).await;
+    Ok(matches!(connect, Ok(Ok(_))))
+}
+
+// Set by the Linode CCM on Services it has provisioned a NodeBalancer for.
+pub const NODEBALANCER_ID_ANNOTATION: &str = "service.beta.kubernetes.io/linode-loadbalancer-nodebalancer-id";
+
+// Turn a HealthCheck's scope into NodeBalancer and config IDs. The
+// NodeBalancer behind a Service is taken from the CCM annotation or found
+// by its load balancer ingress IP.
+pub async fn resolve_scope(client: Client, scope: &Option<NodeBalancerScope>, default_namespace: &str) -> Result<NbScope, Error> {
+    let Some(scope) = scope else { return Ok(NbScope::default()) };
+    let mut nodebalancer_ids = scope.nodebalancer_ids.clone();
+    if let Some(service_ref) = &scope.service {
+        let namespace = service_ref.namespace.as_deref().unwrap_or(default_namespace);
+        let service = Api::<Service>::namespaced(client, namespace).get(&service_ref.name).await?;
+        let mut service_nb = annotated_nodebalancer(&service)
+            .map_err(|e| Error::UserInputError(format!("Service {}/{} has {}", namespace, service_ref.name, e)))?;
+        if service_nb.is_none() {
+            let ingress = service.status.and_then(|s| s.load_balancer).and_then(|lb| lb.ingress).unwrap_or_default();
+            for ip in ingress.into_iter().filter_map(|i| i.ip) {
+                service_nb = store().get_nodebalancer_by_ip(&ip).await?;
+                if service_nb.is_some() {
+                    break;
+                }
+            }
         }
-    } else {
-        result = false;
+        let mut ids = nodebalancer_ids.unwrap_or_default();
+        match service_nb {
+            Some(id) => ids.push(id),
+            None => println!("No NodeBalancer found for Service {}/{} yet", namespace, service_ref.name),
+        }
+        nodebalancer_ids = Some(ids);
     }
-    result
+    Ok(NbScope {
+        nodebalancer_ids,
+        config_ids: scope.config_ids.clone(),
+    })
+}
 
+// The NodeBalancer ID the Linode CCM annotated a Service with, if any.
+fn annotated_nodebalancer(service: &Service) -> Result<Option<i32>, String> {
+    let Some(id) = service.annotations().get(NODEBALANCER_ID_ANNOTATION) else { return Ok(None) };
+    id.trim().parse().map(Some).map_err(|_| format!("invalid {} {:?}", NODEBALANCER_ID_ANNOTATION, id))
 }
 
-pub async fn remove_from_nb(client: Client, name: &str, port: i32, podip: String, clustername: &String) {
+// One target's probe result.
+#[derive(Debug, Clone)]
+pub struct Verdict {
+    pub healthy: bool,
+    pub reason: &'static str,
+    pub probe_type: &'static str,
+    pub probe_error: Option<String>,
+    pub latency_ms: Option<f64>,
+}
+
+impl Verdict {
+    pub fn new(healthy: bool, reason: &'static str) -> Self {
+        Verdict { healthy, reason, probe_type: "none", probe_error: None, latency_ms: None }
+    }
+}
+
+// Targets behind the same NodeBalancer nodes share one state row per
+// NodeBalancer node, so their verdicts are combined before the mode is set:
+// healthy only if every target is, and otherwise reported as the first
+// failing target. Returns that target's index with the combined verdict,
+// which carries every probe error and the slowest latency.
+pub fn combine_verdicts(verdicts: &[Verdict]) -> Option<(usize, Verdict)> {
+    let index = verdicts.iter().position(|v| !v.healthy).or((!verdicts.is_empty()).then_some(0))?;
+    let errors: Vec<&str> = verdicts.iter().filter_map(|v| v.probe_error.as_deref()).collect();
+    let combined = Verdict {
+        probe_error: (!errors.is_empty()).then(|| errors.join("; ")),
+        latency_ms: verdicts.iter().filter_map(|v| v.latency_ms).reduce(f64::max),
+        ..verdicts[index].clone()
+    };
+    Some((index, combined))
+}
+
+// Why a mode is being set, for the audit log.
+pub struct Decision<'a> {
+    pub healthcheck: &'a str,
+    pub healthcheck_uid: &'a str,
+    pub pod_uid: &'a str,
+    pub scope: &'a NbScope,
+    pub reason: &'a str,
+    pub probe_type: &'a str,
+    pub probe_error: Option<String>,
+    pub latency_ms: Option<f64>,
+    pub dry_run: bool,
+}
+
+pub async fn remove_from_nb(client: Client, name: &str, port: i32, podip: IpAddr, clustername: &String, decision: &Decision<'_>) -> Result<(), Error> {
+    set_nb_mode(client, name, port, podip, clustername, "drain", decision).await
+}
+
+// NodeBalancer nodes backed by this node's private address on the given port.
+// On dual-stack nodes the address of the same family as the pod IP is used,
+// falling back to the other family if the node has none.
+async fn get_nb_nodes(client: Client, name: &str, port: i32, podip: IpAddr, scope: &NbScope) -> Result<Vec<NbNode>, Error> {
     let api: Api<Node> = Api::all(client);
-    let node = api.get(&name).await.unwrap();
-    let private_ip = get_private_address(&node);
-    let dbresp = get_by_node_ip_nbcfg(&private_ip.unwrap(), &port).await;
-    let response = dbresp.unwrap();
-    let mode = "drain";
-    let hcstatus = "drain";
-    for row in response {
-        let nodeid: i32 = row.get(0);
-        let cfgid: i32 = row.get(3);
-        let nbid: i32 = row.get(4);
-        println!("REMOVE: Node ID {} = Config ID {} = NodeBalancer ID {} = Port {}", nodeid, cfgid, nbid, port);
-        let _ = hcapi::change_node_mode(&nbid, &cfgid, &nodeid, (&mode).to_string()).await;
-        let _ = update_state(nbid, cfgid, nodeid, &podip, port, (&mode).to_string(), (&hcstatus).to_string(), clustername).await;
+    let node = api.get(name).await?;
+    let addresses = get_private_addresses(&node);
+    let (same_family, other_family): (Vec<IpAddr>, Vec<IpAddr>) = addresses.into_iter().partition(|ip| ip.is_ipv4() == podip.is_ipv4());
+    let private_ips = if same_family.is_empty() { other_family } else { same_family };
+    if private_ips.is_empty() {
+        return Err(Error::MissingNodeAddress(name.to_string()));
+    }
+    let mut nb_nodes = Vec::new();
+    for private_ip in private_ips {
+        for nb_node in store().get_by_node_ip_nbcfg(private_ip, port, scope).await? {
+            if !nb_nodes.contains(&nb_node) {
+                nb_nodes.push(nb_node);
+            }
+        }
+    }
+    Ok(nb_nodes)
+}
+
+// Tag marking NodeBalancers as this cluster's, defaults to the cluster name.
+static OWNER_TAG: LazyLock<Option<String>> = LazyLock::new(|| env::var("NODEBALANCER_OWNER_TAG").ok().filter(|v| !v.is_empty()));
+static OWNERSHIP_CHECK: LazyLock<bool> = LazyLock::new(|| env::var("OWNERSHIP_CHECK").map_or(true, |v| v != "false"));
 
+// A NodeBalancer belongs to this cluster if its LKE cluster ID is the
+// cluster identity or it carries the owner tag. The NodeBalancer is read
+// from the API rather than the inventory, so a stale row can't vouch for it.
+pub async fn check_ownership(nbid: i32, clustername: &str) -> Result<(), Error> {
+    if !*OWNERSHIP_CHECK {
+        return Ok(());
     }
+    let nodebalancer = hcapi::get_nodebalancer(&nbid)
+        .await
+        .map_err(|e| Error::NodeBalancerError(format!("could not read NodeBalancer {} to check ownership: {}", nbid, e)))?;
+    let lke_id = nodebalancer.lke_cluster.as_ref().map(|lke| lke.id);
+    if lke_id.is_some_and(|id| id.to_string() == clustername) {
+        return Ok(());
+    }
+    let tag = OWNER_TAG.as_deref().unwrap_or(clustername);
+    if nodebalancer.tags.iter().any(|t| t == tag) {
+        return Ok(());
+    }
+    crate::metrics::inc("hc_operator_ownership_refused_total", &[("nodebalancer_id", &nbid.to_string())]);
+    Err(Error::OwnershipError(format!(
+        "NodeBalancer {} has LKE cluster {} and tags {:?}, expected cluster {} or tag {}",
+        nbid,
+        lke_id.map_or("none".to_string(), |id| id.to_string()),
+        nodebalancer.tags,
+        clustername,
+        tag
+    )))
+}
+
+// How long a NodeBalancer owned by another cluster is skipped before its
+// ownership is checked again.
+const OWNERSHIP_RETRY: Duration = Duration::from_secs(300);
 
+static RECENTLY_REFUSED: LazyLock<Mutex<HashMap<i32, Instant>>> = LazyLock::new(Default::default);
+
+fn recently_refused(nbid: i32) -> bool {
+    let mut refused = RECENTLY_REFUSED.lock().unwrap();
+    refused.retain(|_, at| at.elapsed() < OWNERSHIP_RETRY);
+    refused.contains_key(&nbid)
 }
 
-pub async fn get_state(port: i32, podip: String, clustername: &String) -> (String, String) {
-    let result = get_db_state(port, podip, &clustername).await;
-    let mut lastmode: String = String::new();
-    let mut current: String = String::new();
-    for row in result.unwrap() {
+// NodeBalancers among nb_nodes that failed check_ownership, with the reason.
+// One owned by another cluster is then skipped for OWNERSHIP_RETRY without
+// being checked, logged or audited again, and comes back with no reason.
+async fn refused_nodebalancers(nb_nodes: &[NbNode], clustername: &str) -> BTreeMap<i32, Option<Error>> {
+    let mut refused = BTreeMap::new();
+    let nbids: BTreeSet<i32> = nb_nodes.iter().map(|n| n.nodebalancer_id).collect();
+    for nbid in nbids {
+        if recently_refused(nbid) {
+            refused.insert(nbid, None);
+            continue;
+        }
+        if let Err(e) = check_ownership(nbid, clustername).await {
+            println!("Refusing to change NodeBalancer {}: {}", nbid, e);
+            if matches!(e, Error::OwnershipError(_)) {
+                RECENTLY_REFUSED.lock().unwrap().insert(nbid, Instant::now());
+            }
+            refused.insert(nbid, Some(e));
+        }
+    }
+    refused
+}
 
-        lastmode = row.get(5);
-        current = row.get(6);
+static COMBINE_POLICY: LazyLock<String> = LazyLock::new(|| env::var("COMBINE_POLICY").unwrap_or("any-fail-drains".to_string()));
 
+// Mode of a NodeBalancer node given the verdicts of every HealthCheck that
+// covers it. With COMBINE_POLICY=any-fail-drains (the default) one failing
+// check takes the node out, with majority more than half must accept. A
+// node that is out gets the strictest mode among the checks.
+pub fn combined_mode(rows: &[StateRow]) -> Option<String> {
+    let verdicts: Vec<&str> = rows
+        .iter()
+        .map(|row| row.current.as_str())
+        .filter(|mode| matches!(*mode, "accept" | "drain" | "reject" | "backup"))
+        .collect();
+    if verdicts.is_empty() {
+        return None;
+    }
+    let accepting = verdicts.iter().filter(|mode| **mode == "accept").count();
+    let accept = if *COMBINE_POLICY == "majority" { accepting * 2 > verdicts.len() } else { accepting == verdicts.len() };
+    if accept {
+        return Some("accept".to_string());
     }
+    let mode = ["reject", "backup", "drain"].into_iter().find(|mode| verdicts.contains(mode)).unwrap_or("drain");
+    Some(mode.to_string())
+}
+
+// In dry run the decision is only written to the shadow_state table. Every
+// real change is appended to the audit log, whether or not the API call worked.
+// A failed API call doesn't stop the remaining NodeBalancer nodes from being
+// changed, but is returned once they have all been tried. NodeBalancers
+// owned by another cluster are never changed; the refusal is audited and
+// the other NodeBalancer nodes are changed as usual. mode is this HealthCheck's verdict; the NodeBalancer node
+// is only changed if that changes its combined_mode.
+pub async fn set_nb_mode(client: Client, name: &str, port: i32, podip: IpAddr, clustername: &String, mode: &str, decision: &Decision<'_>) -> Result<(), Error> {
+    let response = get_nb_nodes(client, name, port, podip, decision.scope).await?;
+    let refused = refused_nodebalancers(&response, clustername).await;
+    let podip = podip.to_string();
+    let mut failures = Vec::new();
+    for nb_node in response {
+        let nodeid = nb_node.node_id;
+        let cfgid = nb_node.config_id;
+        let nbid = nb_node.nodebalancer_id;
+        let refusal = match refused.get(&nbid) {
+            Some(None) => continue,
+            Some(Some(e)) => Some(e),
+            None => None,
+        };
+        let update = StateUpdate {
+            healthcheck: decision.healthcheck_uid.to_string(),
+            node_name: name.to_string(),
+            nodebalancer_id: nbid,
+            nodebalancer_config_id: cfgid,
+            node_id: nodeid,
+            podip: podip.clone(),
+            pod_uid: decision.pod_uid.to_string(),
+            port,
+            lastmode: mode.to_string(),
+            current: mode.to_string(),
+            cluster_name: clustername.to_string(),
+        };
+        if decision.dry_run {
+            if refusal.is_some() {
+                continue;
+            }
+            println!("DRY RUN {}: Node ID {} = Config ID {} = NodeBalancer ID {} = Port {}", mode.to_uppercase(), nodeid, cfgid, nbid, port);
+            store().update_state(&update, true).await?;
+            continue;
+        }
+        let mut rows = store().get_nb_node_states(nodeid, clustername, false).await?;
+        let old_mode = combined_mode(&rows);
+        rows.retain(|row| row.healthcheck != update.healthcheck || row.node_name != update.node_name);
+        rows.push(StateRow { current: mode.to_string(), ..Default::default() });
+        let new_mode = combined_mode(&rows).unwrap_or(mode.to_string());
+        if old_mode.as_deref() == Some(new_mode.as_str()) && refusal.is_none() {
+            println!("{}: Node ID {} = Config ID {} = NodeBalancer ID {} stays {} with the other HealthChecks", mode.to_uppercase(), nodeid, cfgid, nbid, new_mode);
+            store().update_state(&update, false).await?;
+            continue;
+        }
+        println!("{}: Node ID {} = Config ID {} = NodeBalancer ID {} = Port {}", new_mode.to_uppercase(), nodeid, cfgid, nbid, port);
+        let api_result = match refusal {
+            Some(e) => Err(e.to_string()),
+            None => hcapi::change_node_mode(&nbid, &cfgid, &nodeid, new_mode.clone()).await.map_err(|e| e.to_string()),
+        };
+        let record = AuditRecord {
+            changed_at: chrono::Utc::now(),
+            cluster_name: clustername.to_string(),
+            node_name: name.to_string(),
+            podip: podip.clone(),
+            nodebalancer_id: nbid,
+            nodebalancer_config_id: cfgid,
+            node_id: nodeid,
+            old_mode: old_mode.unwrap_or_default(),
+            new_mode: new_mode.clone(),
+            healthcheck: decision.healthcheck.to_string(),
+            reason: decision.reason.to_string(),
+            probe_type: decision.probe_type.to_string(),
+            error: api_result.clone().err().or(decision.probe_error.clone()),
+            latency_ms: decision.latency_ms,
+            api_success: api_result.is_ok(),
+        };
+        if let Err(e) = store().record_audit(&record).await {
+            println!("Failed to write audit record: {:?}", e);
+        }
+        if let Err(e) = api_result {
+            if !matches!(refusal, Some(Error::OwnershipError(_))) {
+                println!("Failed to set NodeBalancer {} node {} to {}: {}", nbid, nodeid, new_mode, e);
+                failures.push(format!("NodeBalancer {} node {}: {}", nbid, nodeid, e));
+            }
+            continue;
+        }
+        store().update_state(&update, false).await?;
+
+    }
+    if !failures.is_empty() {
+        return Err(Error::NodeBalancerError(failures.join("; ")));
+    }
+    Ok(())
 
-    (lastmode, current)
 }
 
-pub async fn init_state(client: Client, name: &str, port: i32, podip: String, clustername: &String) {
-    let api: Api<Node> = Api::all(client);
-    let node = api.get(&name).await.unwrap();
-    let private_ip = get_private_address(&node);
-    let dbresp = get_by_node_ip_nbcfg(&private_ip.unwrap(), &port).await;
-    let response = dbresp.unwrap();
-    let mode = "none";
-    let hcstatus = "none";
-    for row in response {
-        let nodeid: i32 = row.get(0);
-        let cfgid: i32 = row.get(3);
-        let nbid: i32 = row.get(4);
-        let _ = update_state(nbid, cfgid, nodeid, &podip, port, (&mode).to_string(), (&hcstatus).to_string(), clustername).await;
+#[derive(Debug, Default)]
+pub struct NodeState {
+    pub podip: String,
+    pub lastmode: String,
+    pub current: String,
+    pub drain_started: Option<i64>,
+    pub latency_ms: Option<f64>,
+    pub weight: Option<i32>,
+    // NodeBalancer nodes the rows cover.
+    pub node_ids: Vec<i32>,
+}
+
+// Why the drift check leaves a NodeBalancer node alone: its node has an
+// active override, or a HealthCheck covering it is in a maintenance window.
+// Either way the reconcile is holding the node back on purpose.
+fn drift_skip_reason(rows: &[StateRow], node: Option<&Node>, in_maintenance: &BTreeSet<String>) -> Option<&'static str> {
+    if node.is_some_and(|node| get_override(node).is_some()) {
+        return Some("node override");
+    }
+    if rows.iter().any(|row| in_maintenance.contains(&row.healthcheck)) {
+        return Some("maintenance window");
     }
+    None
 }
 
-pub async fn add_to_nb(client: Client, name: &str, port: i32, podip: String, clustername: &String) {
-    let api: Api<Node> = Api::all(client);
-    let node = api.get(&name).await.unwrap();
-    let private_ip = get_private_address(&node);
-    let dbresp = get_by_node_ip_nbcfg(&private_ip.unwrap(), &port).await;
-    let response = dbresp.unwrap();
-    let mode = "accept";
-    let hcstatus = "accept";
-    for row in response {
-        let nodeid: i32 = row.get(0);
-        let cfgid: i32 = row.get(3);
-        let nbid: i32 = row.get(4);
-        println!("ADD: Node ID {} = Config ID {} = NodeBalancer ID {} = Port {}", nodeid, cfgid, nbid, port);
-        let _ = hcapi::change_node_mode(&nbid, &cfgid, &nodeid, (&mode).to_string()).await;
-        let _ = update_state(nbid, cfgid, nodeid, &podip, port, (&mode).to_string(), (&hcstatus).to_string(), clustername).await;
+// Compare the combined mode of the state rows for each NodeBalancer node
+// with its actual mode. Drift is either corrected on the NodeBalancer or,
+// with correct set to false, written back to every row of the node.
+pub async fn check_drift(client: Client, recorder: &Recorder, clustername: &str, correct: bool) {
+    let rows = match store().get_cluster_states(clustername, false).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            println!("Drift check could not read state: {:?}", e);
+            return;
+        }
+    };
+    let nodes = match Api::<Node>::all(client.clone()).list(&ListParams::default()).await {
+        Ok(nodes) => nodes.items,
+        Err(e) => {
+            println!("Drift check could not list nodes: {:?}", e);
+            return;
+        }
+    };
+    let in_maintenance: BTreeSet<String> = match Api::<HealthCheck>::namespaced(client, "default").list(&ListParams::default()).await {
+        Ok(list) => list
+            .items
+            .into_iter()
+            .filter(|hc| crate::maintenance::in_maintenance(&hc.spec.maintenance_windows, chrono::Utc::now()).unwrap_or(false))
+            .filter_map(|hc| hc.uid())
+            .collect(),
+        Err(e) => {
+            println!("Drift check could not list HealthChecks: {:?}", e);
+            return;
+        }
+    };
+    let mut nb_nodes: BTreeMap<(i32, i32, i32), Vec<StateRow>> = BTreeMap::new();
+    for row in rows {
+        nb_nodes.entry((row.nodebalancer_id, row.nodebalancer_config_id, row.node_id)).or_default().push(row);
+    }
+    for ((nbid, cfgid, nodeid), rows) in nb_nodes {
+        let Some(current) = combined_mode(&rows) else { continue };
+        let row = &rows[0];
+        let node = nodes.iter().find(|n| rows.iter().any(|r| r.node_name == n.name_any()));
+        if let Some(reason) = drift_skip_reason(&rows, node, &in_maintenance) {
+            println!("Drift check skipping NodeBalancer {} node {} - {}", nbid, nodeid, reason);
+            continue;
+        }
+        let actual = match hcapi::get_node(&nbid, &cfgid, &nodeid).await {
+            Ok(nb_node) => nb_node,
+            Err(e) => {
+                println!("Drift check could not get NodeBalancer {} node {}: {:?}", nbid, nodeid, e);
+                continue;
+            }
+        };
+        if actual.mode == current {
+            continue;
+        }
+        println!("DRIFT: Node ID {} = Config ID {} = NodeBalancer ID {} - state {} actual {}", nodeid, cfgid, nbid, current, actual.mode);
+        let resolution = if correct {
+            let ownership = check_ownership(nbid, clustername).await;
+            let api_result = match &ownership {
+                Ok(()) => hcapi::change_node_mode(&nbid, &cfgid, &nodeid, current.clone()).await.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            let record = AuditRecord {
+                changed_at: chrono::Utc::now(),
+                cluster_name: row.cluster_name.clone(),
+                node_name: node.map(|n| n.name_any()).unwrap_or_default(),
+                podip: row.podip.clone(),
+                nodebalancer_id: nbid,
+                nodebalancer_config_id: cfgid,
+                node_id: nodeid,
+                old_mode: actual.mode.clone(),
+                new_mode: current.clone(),
+                healthcheck: String::new(),
+                reason: "NodeBalancerDrift".to_string(),
+                probe_type: "none".to_string(),
+                error: api_result.clone().err(),
+                latency_ms: None,
+                api_success: api_result.is_ok(),
+            };
+            if let Err(e) = store().record_audit(&record).await {
+                println!("Failed to write audit record: {:?}", e);
+            }
+            match api_result {
+                Ok(()) => "corrected",
+                Err(e) if ownership.is_err() => {
+                    println!("Refusing to correct NodeBalancer {} node {}: {}", nbid, nodeid, e);
+                    "refused"
+                }
+                Err(e) => {
+                    println!("Failed to correct NodeBalancer {} node {}: {}", nbid, nodeid, e);
+                    "failed"
+                }
+            }
+        } else {
+            for row in &rows {
+                let update = StateUpdate {
+                    healthcheck: row.healthcheck.clone(),
+                    node_name: row.node_name.clone(),
+                    nodebalancer_id: nbid,
+                    nodebalancer_config_id: cfgid,
+                    node_id: nodeid,
+                    podip: row.podip.clone(),
+                    pod_uid: row.pod_uid.clone(),
+                    port: row.port,
+                    lastmode: actual.mode.clone(),
+                    current: actual.mode.clone(),
+                    cluster_name: row.cluster_name.clone(),
+                };
+                if let Err(e) = store().update_state(&update, false).await {
+                    println!("{:?}", e);
+                }
+            }
+            "recorded"
+        };
+        crate::metrics::inc("hc_operator_drift_total", &[("resolution", resolution)]);
+
+        let Some(node) = node else { continue };
+        let event = Event {
+            type_: EventType::Warning,
+            reason: "NodeBalancerDrift".to_string(),
+            note: Some(format!("NodeBalancer {} config {} node {} was {} but state was {} - {}", nbid, cfgid, nodeid, actual.mode, current, resolution)),
+            action: "DriftCheck".to_string(),
+            secondary: None,
+        };
+        if let Err(e) = recorder.publish(&event, &node.object_ref(&())).await {
+            println!("Failed to publish event for node {:?}: {:?}", node.metadata.name, e);
+        }
+    }
+}
+
+// Delete state rows that no longer describe a live target: the HealthCheck
+// or node is gone, or the pod IP no longer belongs to a running pod on the
+// node. A row whose IP now belongs to a different pod is deleted too, so the
+// new pod starts without the old one's mode. Rows for node addresses are
+// kept while the node has the address. The NodeBalancer isn't changed; the
+// next reconcile of the node writes fresh state.
+pub async fn collect_garbage(client: Client, clustername: &str) {
+    let mut tables = Vec::new();
+    for shadow in [false, true] {
+        match store().get_cluster_states(clustername, shadow).await {
+            Ok(rows) => tables.push((shadow, rows)),
+            Err(e) => {
+                println!("State GC could not read state: {:?}", e);
+                return;
+            }
+        }
+    }
+    let healthchecks: HashMap<String, HealthCheck> = match Api::<HealthCheck>::namespaced(client.clone(), "default").list(&ListParams::default()).await {
+        Ok(list) => list.items.into_iter().filter_map(|hc| Some((hc.uid()?, hc))).collect(),
+        Err(e) => {
+            println!("State GC could not list HealthChecks: {:?}", e);
+            return;
+        }
+    };
+    let node_list = match Api::<Node>::all(client.clone()).list(&ListParams::default()).await {
+        Ok(list) => list.items,
+        Err(e) => {
+            println!("State GC could not list nodes: {:?}", e);
+            return;
+        }
+    };
+    let nodes: HashMap<String, Vec<IpAddr>> = node_list.iter().map(|node| (node.name_any(), get_private_addresses(node))).collect();
+    // Running pods per namespace, as (node, pod IP) -> pod UID.
+    let mut pods: HashMap<String, HashMap<(String, String), String>> = HashMap::new();
+    for hc in healthchecks.values() {
+        let ns = &hc.spec.serv_namespace;
+        if hc.spec.target != ProbeTarget::PodIP || pods.contains_key(ns) {
+            continue;
+        }
+        let list = match Api::<Pod>::namespaced(client.clone(), ns).list(&ListParams::default()).await {
+            Ok(list) => list.items,
+            Err(e) => {
+                println!("State GC could not list pods in {}: {:?}", ns, e);
+                return;
+            }
+        };
+        let mut running = HashMap::new();
+        for pod in list {
+            let phase = pod.status.as_ref().and_then(|s| s.phase.as_deref());
+            let node_name = pod.spec.as_ref().and_then(|s| s.node_name.clone());
+            let (Some("Running"), Some(node_name)) = (phase, node_name) else { continue };
+            for ip in pod_addresses(&pod) {
+                running.insert((node_name.clone(), ip.to_string()), pod.uid().unwrap_or_default());
+            }
+        }
+        pods.insert(ns.clone(), running);
+    }
+
+    for (shadow, rows) in tables {
+        for row in rows {
+            let reason = match (healthchecks.get(&row.healthcheck), nodes.get(&row.node_name)) {
+                (None, _) => "HealthCheckDeleted",
+                (_, None) => "NodeDeleted",
+                (Some(_), Some(addresses)) if row.pod_uid.is_empty() && addresses.iter().any(|ip| ip.to_string() == row.podip) => continue,
+                (Some(hc), Some(_)) => match pods.get(&hc.spec.serv_namespace).and_then(|running| running.get(&(row.node_name.clone(), row.podip.clone()))) {
+                    Some(uid) if *uid == row.pod_uid => continue,
+                    Some(_) => "PodReplaced",
+                    None if row.pod_uid.is_empty() => "AddressGone",
+                    None => "PodGone",
+                },
+            };
+            println!("State GC: removing {} {} node {} pod IP {} NodeBalancer node {} - {}", if shadow { "shadow state" } else { "state" }, row.healthcheck, row.node_name, row.podip, row.node_id, reason);
+            match store().delete_state(&row.healthcheck, &row.node_name, row.node_id, shadow).await {
+                Ok(()) => crate::metrics::inc("hc_operator_state_gc_total", &[("reason", reason)]),
+                Err(e) => println!("State GC could not remove row: {:?}", e),
+            }
+        }
+    }
+
+    let hc_names: BTreeSet<String> = healthchecks.values().map(|hc| hc.name_any()).collect();
+    for node in &node_list {
+        let keys = stale_verdict_keys(node, &hc_names);
+        if !keys.is_empty() {
+            clear_node_verdicts(client.clone(), node, &keys).await;
+        }
+    }
+}
+
+// This HealthCheck's state for the node's targets of podip's address family
+// on one NodeBalancer port, over all of their NodeBalancer nodes. Rows that
+// disagree, e.g. after some API calls failed, come back as "mixed" so the
+// verdict is applied again.
+pub async fn get_state(healthcheck: &str, node_name: &str, port: i32, podip: IpAddr, dry_run: bool) -> Result<NodeState, Error> {
+    let rows: Vec<StateRow> = store()
+        .get_state(healthcheck, node_name, dry_run)
+        .await?
+        .into_iter()
+        .filter(|row| row.port == port && row.podip.parse::<IpAddr>().is_ok_and(|ip| ip.is_ipv4() == podip.is_ipv4()))
+        .collect();
+    let mut state = NodeState::default();
+    if let Some(row) = rows.first() {
+
+        state.podip = row.podip.clone();
+        state.lastmode = row.lastmode.clone();
+        state.current = if rows.iter().all(|r| r.current == row.current) { row.current.clone() } else { "mixed".to_string() };
+        state.drain_started = rows.iter().filter_map(|r| r.drain_started).min();
+        state.latency_ms = row.latency_ms;
+        state.weight = row.weight;
+        state.node_ids = rows.iter().map(|r| r.node_id).collect();
+
+    }
+
+    Ok(state)
+}
+
+// Weight for a slow but reachable node, returned with the smoothed latency.
+// Latency is averaged across probes and small weight changes are skipped so
+// weights don't oscillate.
+pub fn next_weight(state: &NodeState, latency_ms: f64, weighting: &Weighting) -> (f64, i32) {
+    let smoothed = match state.latency_ms {
+        Some(previous) => weighting.smoothing * latency_ms + (1.0 - weighting.smoothing) * previous,
+        None => latency_ms,
+    };
+    let mut weight = weighting.max_weight;
+    if smoothed > weighting.target_latency_ms as f64 {
+        let scaled = weighting.max_weight as f64 * weighting.target_latency_ms as f64 / smoothed;
+        weight = (scaled.round() as i32).clamp(weighting.min_weight, weighting.max_weight);
+    }
+    let Some(previous) = state.weight else { return (smoothed, weight) };
+    let at_limit = weight == weighting.min_weight || weight == weighting.max_weight;
+    if (weight - previous).abs() < weighting.min_step && !at_limit {
+        return (smoothed, previous);
+    }
+    (smoothed, weight)
+}
+
+pub async fn set_nb_weight(client: Client, name: &str, port: i32, podip: IpAddr, clustername: &str, scope: &NbScope, weight: i32) -> Result<(), Error> {
+    let response = get_nb_nodes(client, name, port, podip, scope).await?;
+    let refused = refused_nodebalancers(&response, clustername).await;
+    let mut failures = Vec::new();
+    for nb_node in response {
+        let nodeid = nb_node.node_id;
+        let cfgid = nb_node.config_id;
+        let nbid = nb_node.nodebalancer_id;
+        match refused.get(&nbid) {
+            Some(Some(e)) if !matches!(e, Error::OwnershipError(_)) => {
+                failures.push(format!("NodeBalancer {} node {}: {}", nbid, nodeid, e));
+                continue;
+            }
+            Some(_) => continue,
+            None => (),
+        }
+        println!("WEIGHT: Node ID {} = Config ID {} = NodeBalancer ID {} = Port {} = Weight {}", nodeid, cfgid, nbid, port, weight);
+        if let Err(e) = hcapi::change_node_weight(&nbid, &cfgid, &nodeid, weight).await.map_err(|e| e.to_string()) {
+            failures.push(format!("NodeBalancer {} node {}: {}", nbid, nodeid, e));
+        }
+    }
+    if !failures.is_empty() {
+        return Err(Error::NodeBalancerError(failures.join("; ")));
+    }
+    Ok(())
+}
+
+pub async fn add_to_nb(client: Client, name: &str, port: i32, podip: IpAddr, clustername: &String, decision: &Decision<'_>) -> Result<(), Error> {
+    set_nb_mode(client, name, port, podip, clustername, "accept", decision).await
+}
+
+// Copy the NodeBalancer inventory from the Linode API into the state store,
+// for stores that aren't filled in by another job. Rows the API no longer
+// returns are deleted, but only after a sync in which every list call
+// worked, so a failed call can't empty the inventory.
+pub async fn sync_inventory() {
+    let nodebalancers = match hcapi::list_nodebalancers().await {
+        Ok(nodebalancers) => nodebalancers,
+        Err(e) => {
+            println!("Inventory sync could not list NodeBalancers: {:?}", e);
+            return;
+        }
+    };
+    let (mut nbids, mut cfgids, mut nodeids) = (Vec::new(), Vec::new(), Vec::new());
+    let mut complete = true;
+    for nb in nodebalancers {
+        nbids.push(nb.id);
+        let local = LocalNodeBalancerListObject {
+            nb_id: nb.id,
+            ipv4: nb.ipv4.clone(),
+            region: nb.region.clone(),
+            lke_id: nb.lke_cluster.as_ref().map_or(0, |lke| lke.id),
+        };
+        if let Err(e) = store().update_db_nb(local).await {
+            println!("{:?}", e);
+        }
+        let configs = match hcapi::list_configs(&nb.id).await {
+            Ok(configs) => configs,
+            Err(e) => {
+                println!("Inventory sync could not list configs of NodeBalancer {}: {:?}", nb.id, e);
+                complete = false;
+                continue;
+            }
+        };
+        for config in configs {
+            let cfgid = config.id;
+            cfgids.push(cfgid);
+            if let Err(e) = store().update_db_config(config).await {
+                println!("{:?}", e);
+            }
+            let nodes = match hcapi::list_nodes(&nb.id, &cfgid).await {
+                Ok(nodes) => nodes,
+                Err(e) => {
+                    println!("Inventory sync could not list nodes of config {}: {:?}", cfgid, e);
+                    complete = false;
+                    continue;
+                }
+            };
+            for node in nodes {
+                nodeids.push(node.id);
+                if let Err(e) = store().update_db_node(node).await {
+                    println!("{:?}", e);
+                }
+            }
+        }
+    }
+    if !complete {
+        println!("Inventory sync incomplete - keeping rows the API didn't return");
+        return;
+    }
+    if let Err(e) = store().prune_inventory(&nbids, &cfgids, &nodeids).await {
+        println!("Inventory sync could not delete stale rows: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn node(annotations: serde_json::Value) -> Node {
+        serde_json::from_value(json!({ "metadata": { "name": "node-a", "annotations": annotations } })).unwrap()
+    }
+
+    #[test]
+    fn get_override_values() {
+        assert_eq!(get_override(&node(json!({}))), None);
+        assert_eq!(get_override(&node(json!({ OVERRIDE_ANNOTATION: "accept" }))), Some(NodeOverride::Accept));
+        assert_eq!(get_override(&node(json!({ OVERRIDE_ANNOTATION: " drain " }))), Some(NodeOverride::Drain));
+        assert_eq!(get_override(&node(json!({ OVERRIDE_ANNOTATION: "ignore" }))), Some(NodeOverride::Ignore));
+        assert_eq!(get_override(&node(json!({ OVERRIDE_ANNOTATION: "pause" }))), None);
+    }
+
+    #[test]
+    fn get_override_expiry() {
+        let future = (chrono::Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+        let past = (chrono::Utc::now() - chrono::Duration::hours(1)).to_rfc3339();
+        let expiring = |expires: &str| node(json!({ OVERRIDE_ANNOTATION: "drain", OVERRIDE_EXPIRES_ANNOTATION: expires }));
+        assert_eq!(get_override(&expiring(&future)), Some(NodeOverride::Drain));
+        assert_eq!(get_override(&expiring(&past)), None);
+        assert_eq!(get_override(&expiring("tomorrow")), None);
+    }
+
+
+    #[test]
+    fn get_drain_trigger_reasons() {
+        assert_eq!(get_drain_trigger(&node(json!({}))), None);
+        assert_eq!(get_drain_trigger(&node(json!({ DELETE_MACHINE_ANNOTATION: "" }))), Some("MachineDeletion"));
+        let mut cordoned = node(json!({}));
+        cordoned.spec = Some(k8s_openapi::api::core::v1::NodeSpec { unschedulable: Some(true), ..Default::default() });
+        assert_eq!(get_drain_trigger(&cordoned), Some("Cordoned"));
+        cordoned.metadata.annotations = Some(BTreeMap::from([(DELETE_MACHINE_ANNOTATION.to_string(), String::new())]));
+        assert_eq!(get_drain_trigger(&cordoned), Some("MachineDeletion"));
+    }
+
+    fn pod(gate: bool, conditions: serde_json::Value, containers: serde_json::Value) -> Pod {
+        let gates = if gate { json!([{ "conditionType": READINESS_GATE }]) } else { json!([]) };
+        serde_json::from_value(json!({
+            "metadata": { "name": "web-1" },
+            "spec": { "containers": [], "readinessGates": gates },
+            "status": { "conditions": conditions, "containerStatuses": containers },
+        }))
+        .unwrap()
+    }
+
+    fn condition(type_: &str, status: &str) -> serde_json::Value {
+        json!({ "type": type_, "status": status })
+    }
+
+    fn container(state: serde_json::Value, last_state: serde_json::Value) -> serde_json::Value {
+        json!({ "name": "app", "image": "app", "imageID": "", "ready": true, "restartCount": 3, "state": state, "lastState": last_state })
+    }
+
+    #[test]
+    fn pod_not_ready_uses_ready_condition() {
+        assert_eq!(pod_not_ready(&pod(false, json!([condition("Ready", "True")]), json!([]))), None);
+        assert!(pod_not_ready(&pod(false, json!([condition("Ready", "False")]), json!([]))).is_some());
+        assert!(pod_not_ready(&pod(false, json!([]), json!([]))).is_some());
+        let mut no_status = pod(false, json!([]), json!([]));
+        no_status.status = None;
+        assert_eq!(pod_not_ready(&no_status).as_deref(), Some("pod has no status"));
+    }
+
+    #[test]
+    fn pod_not_ready_with_gate_uses_containers_ready() {
+        let conditions = json!([condition("Ready", "False"), condition("ContainersReady", "True")]);
+        assert_eq!(pod_not_ready(&pod(true, conditions.clone(), json!([]))), None);
+        assert!(pod_not_ready(&pod(false, conditions, json!([]))).is_some());
+    }
+
+    #[test]
+    fn pod_not_ready_crash_loop_and_recent_restart() {
+        let ready = json!([condition("Ready", "True")]);
+        let crashing = container(json!({ "waiting": { "reason": "CrashLoopBackOff" } }), json!({}));
+        assert_eq!(pod_not_ready(&pod(false, ready.clone(), json!([crashing]))).as_deref(), Some("container app is in CrashLoopBackOff"));
+        let finished = |ago: i64| (chrono::Utc::now() - chrono::Duration::seconds(ago)).to_rfc3339();
+        let restarted = |ago| container(json!({ "running": {} }), json!({ "terminated": { "exitCode": 1, "finishedAt": finished(ago) } }));
+        assert!(pod_not_ready(&pod(false, ready.clone(), json!([restarted(10)]))).is_some_and(|reason| reason.contains("restarted")));
+        assert_eq!(pod_not_ready(&pod(false, ready, json!([restarted(RECENT_RESTART_SECS + 30)]))), None);
+    }
+
+    #[test]
+    fn readiness_gate_status_needs_gate() {
+        let mut without_gate = pod(false, json!([condition(READINESS_GATE, "True")]), json!([]));
+        assert_eq!(readiness_gate_status(&without_gate), None);
+        without_gate.spec = None;
+        assert_eq!(readiness_gate_status(&without_gate), None);
+        assert_eq!(readiness_gate_status(&pod(true, json!([]), json!([]))), Some(false));
+        assert_eq!(readiness_gate_status(&pod(true, json!([condition(READINESS_GATE, "False")]), json!([]))), Some(false));
+        assert_eq!(readiness_gate_status(&pod(true, json!([condition("Ready", "True"), condition(READINESS_GATE, "True")]), json!([]))), Some(true));
+    }
+
+    #[tokio::test]
+    async fn check_port_connects() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port() as i32;
+        assert!(check_port(IpAddr::from([127, 0, 0, 1]), port, 1).await.unwrap());
+        drop(listener);
+        assert!(!check_port(IpAddr::from([127, 0, 0, 1]), port, 1).await.unwrap());
+        assert!(check_port(IpAddr::from([127, 0, 0, 1]), 70000, 1).await.is_err());
+    }
+
+    #[test]
+    fn stale_verdict_keys_only_for_deleted_healthchecks() {
+        let node: Node = serde_json::from_value(json!({
+            "metadata": {
+                "name": "node-a",
+                "labels": { "hc.example.com/web": "healthy", "hc.example.com/old": "unhealthy", "hc.example.com/other": "x", "app": "healthy" },
+            },
+            "spec": { "taints": [
+                { "key": "hc.example.com/gone", "value": "unhealthy", "effect": "NoSchedule" },
+                { "key": "hc.example.com/web", "value": "unhealthy", "effect": "NoSchedule" },
+                { "key": "hc.example.com/manual", "effect": "NoExecute" },
+            ] },
+        }))
+        .unwrap();
+        let healthchecks = BTreeSet::from(["web".to_string()]);
+        let keys: Vec<String> = stale_verdict_keys(&node, &healthchecks).into_iter().collect();
+        assert_eq!(keys, vec!["hc.example.com/gone", "hc.example.com/old"]);
+        assert!(stale_verdict_keys(&node, &BTreeSet::from(["web".to_string(), "old".to_string(), "gone".to_string()])).is_empty());
+    }
+
+    #[test]
+    fn recently_refused_expires() {
+        RECENTLY_REFUSED.lock().unwrap().insert(-1, Instant::now());
+        assert!(recently_refused(-1));
+        assert!(!recently_refused(-2));
+        if let Some(expired) = Instant::now().checked_sub(OWNERSHIP_RETRY) {
+            RECENTLY_REFUSED.lock().unwrap().insert(-2, expired);
+            assert!(!recently_refused(-2));
+            assert!(!RECENTLY_REFUSED.lock().unwrap().contains_key(&-2));
+        }
+    }
+
+    #[test]
+    fn drift_skip_reason_override_and_maintenance() {
+        let row = |healthcheck: &str| StateRow { healthcheck: healthcheck.to_string(), node_name: "node-a".to_string(), current: "accept".to_string(), ..Default::default() };
+        let rows = vec![row("hc-1"), row("hc-2")];
+        let plain = node(json!({}));
+        let ignored = node(json!({ OVERRIDE_ANNOTATION: "ignore" }));
+        let expired = node(json!({ OVERRIDE_ANNOTATION: "drain", OVERRIDE_EXPIRES_ANNOTATION: "2000-01-01T00:00:00Z" }));
+        let none = BTreeSet::new();
+        assert_eq!(drift_skip_reason(&rows, Some(&plain), &none), None);
+        assert_eq!(drift_skip_reason(&rows, None, &none), None);
+        assert_eq!(drift_skip_reason(&rows, Some(&expired), &none), None);
+        assert_eq!(drift_skip_reason(&rows, Some(&ignored), &none), Some("node override"));
+        let maintenance = BTreeSet::from(["hc-2".to_string()]);
+        assert_eq!(drift_skip_reason(&rows, Some(&plain), &maintenance), Some("maintenance window"));
+        assert_eq!(drift_skip_reason(&rows[..1], Some(&plain), &maintenance), None);
+    }
+
+    #[test]
+    fn annotated_nodebalancer_parses_id() {
+        let service = |annotations: serde_json::Value| -> Service { serde_json::from_value(json!({ "metadata": { "annotations": annotations } })).unwrap() };
+        assert_eq!(annotated_nodebalancer(&service(json!({}))), Ok(None));
+        assert_eq!(annotated_nodebalancer(&service(json!({ NODEBALANCER_ID_ANNOTATION: " 1234 " }))), Ok(Some(1234)));
+        assert!(annotated_nodebalancer(&service(json!({ NODEBALANCER_ID_ANNOTATION: "nb-1234" }))).is_err());
+    }
+
+    #[tokio::test]
+    async fn resolve_scope_without_service() {
+        // Never contacted: without a Service reference nothing is looked up.
+        let client = Client::try_from(kube::Config::new("http://127.0.0.1:9".parse().unwrap())).unwrap();
+        assert_eq!(resolve_scope(client.clone(), &None, "default").await.unwrap(), NbScope::default());
+        let scope = NodeBalancerScope { nodebalancer_ids: Some(vec![1, 2]), config_ids: Some(vec![]), service: None };
+        let resolved = resolve_scope(client, &Some(scope), "default").await.unwrap();
+        assert_eq!(resolved, NbScope { nodebalancer_ids: Some(vec![1, 2]), config_ids: Some(vec![]) });
+        let nb_node = NbNode { node_id: 100, config_id: 10, nodebalancer_id: 1, address: String::new() };
+        assert!(!resolved.matches(&nb_node));
+    }
+
+    #[test]
+    fn combine_verdicts_reports_first_failure() {
+        let verdicts = vec![
+            Verdict { latency_ms: Some(5.0), ..Verdict::new(true, "ProbePassed") },
+            Verdict { probe_error: Some("a".to_string()), latency_ms: Some(9.0), ..Verdict::new(false, "ProbeFailed") },
+            Verdict { probe_error: Some("b".to_string()), ..Verdict::new(false, "PodTerminating") },
+        ];
+        let (index, verdict) = combine_verdicts(&verdicts).unwrap();
+        assert_eq!(index, 1);
+        assert!(!verdict.healthy);
+        assert_eq!(verdict.reason, "ProbeFailed");
+        assert_eq!(verdict.probe_error.as_deref(), Some("a; b"));
+        assert_eq!(verdict.latency_ms, Some(9.0));
+    }
+
+    #[test]
+    fn combine_verdicts_healthy_when_all_pass() {
+        let verdicts = vec![Verdict::new(true, "PodReady"), Verdict::new(true, "PodReady")];
+        let (index, verdict) = combine_verdicts(&verdicts).unwrap();
+        assert_eq!(index, 0);
+        assert!(verdict.healthy);
+        assert_eq!(verdict.latency_ms, None);
+        assert!(combine_verdicts(&[]).is_none());
+    }
+
+    fn rows(modes: &[&str]) -> Vec<StateRow> {
+        modes.iter().map(|mode| StateRow { current: mode.to_string(), ..Default::default() }).collect()
+    }
+
+    // COMBINE_POLICY isn't set in tests, so this is any-fail-drains.
+    #[test]
+    fn combined_mode_any_fail_drains() {
+        assert_eq!(combined_mode(&rows(&[])), None);
+        assert_eq!(combined_mode(&rows(&["", "mixed"])), None);
+        assert_eq!(combined_mode(&rows(&["accept", "accept"])).as_deref(), Some("accept"));
+        assert_eq!(combined_mode(&rows(&["accept", "drain"])).as_deref(), Some("drain"));
+        assert_eq!(combined_mode(&rows(&["accept", "", "accept"])).as_deref(), Some("accept"));
+    }
+
+    #[test]
+    fn combined_mode_takes_strictest() {
+        assert_eq!(combined_mode(&rows(&["drain", "backup"])).as_deref(), Some("backup"));
+        assert_eq!(combined_mode(&rows(&["backup", "reject", "drain"])).as_deref(), Some("reject"));
+        assert_eq!(combined_mode(&rows(&["accept", "drain", "accept"])).as_deref(), Some("drain"));
+    }
+
+    fn weighting() -> Weighting {
+        Weighting { target_latency_ms: 100, min_weight: 10, max_weight: 100, smoothing: 0.5, min_step: 5 }
+    }
+
+    #[test]
+    fn next_weight_scales_with_latency() {
+        let state = NodeState::default();
+        assert_eq!(next_weight(&state, 50.0, &weighting()), (50.0, 100));
+        assert_eq!(next_weight(&state, 200.0, &weighting()), (200.0, 50));
+        assert_eq!(next_weight(&state, 100_000.0, &weighting()), (100_000.0, 10));
+    }
+
+    #[test]
+    fn next_weight_smooths_and_skips_small_steps() {
+        let state = NodeState { latency_ms: Some(200.0), weight: Some(50), ..Default::default() };
+        // (0.5 * 210 + 0.5 * 200) = 205 gives 49, too small a change to send.
+        assert_eq!(next_weight(&state, 210.0, &weighting()), (205.0, 50));
+        // 250 gives 40.
+        assert_eq!(next_weight(&state, 300.0, &weighting()), (250.0, 40));
+        // Back under target goes straight to max_weight, however small the step.
+        let state = NodeState { latency_ms: Some(90.0), weight: Some(98), ..Default::default() };
+        assert_eq!(next_weight(&state, 90.0, &weighting()), (90.0, 100));
     }
 }
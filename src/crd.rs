@@ -1,6 +1,7 @@
 use kube::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 
 #[derive(CustomResource, Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
 #[kube(
@@ -9,10 +10,375 @@ use serde::{Deserialize, Serialize};
     kind = "HealthCheck",
     plural = "healthchecks",
     derive = "PartialEq",
+    status = "HealthCheckStatus",
     namespaced
 )]
 pub struct HealthCheckSpec {
     pub timeout: u64,
+    // Probe and NodeBalancer config port. Ignored when ports is set.
+    #[serde(default)]
     pub port: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ports: Option<Vec<PortMapping>>,
+    // Several TCP probes per target, combined by expression into one
+    // verdict. They replace the probe on the mapping's probe port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probes: Option<Vec<NamedProbe>>,
+    #[serde(default)]
+    pub expression: ProbeExpression,
     pub serv_namespace: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance_windows: Option<Vec<MaintenanceWindow>>,
+    #[serde(default)]
+    pub taint_unhealthy: bool,
+    // Seconds a node stays in drain before moving to drain_escalation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drain_grace_period: Option<u64>,
+    #[serde(default)]
+    pub drain_escalation: DrainEscalation,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weighting: Option<Weighting>,
+    // Probe and decide as usual but never change the NodeBalancer.
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub target: ProbeTarget,
+    #[serde(default)]
+    pub source: ProbeSource,
+    // Probe the endpoints of this Service in serv_namespace instead of every
+    // pod in serv_namespace. Needs target podIP.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    // Verdict for a node with no pods in serv_namespace, in podIP mode.
+    #[serde(default)]
+    pub no_pods: NoPodsPolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<NodeBalancerScope>,
+}
+
+impl HealthCheckSpec {
+    // Settings that don't work together, checked before anything is probed.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.port == 0 && self.ports.as_ref().is_none_or(|ports| ports.is_empty()) {
+            return Err("needs port or ports".to_string());
+        }
+        if self.source == ProbeSource::PodReadiness && self.target == ProbeTarget::NodeIP {
+            return Err("source podReadiness needs target podIP".to_string());
+        }
+        if self.service.is_some() && self.target == ProbeTarget::NodeIP {
+            return Err("service needs target podIP".to_string());
+        }
+        if let Some(probes) = self.probes.as_ref().filter(|probes| !probes.is_empty()) {
+            let mut names = BTreeSet::new();
+            for probe in probes {
+                if !names.insert(probe.name.as_str()) {
+                    return Err(format!("probe name {} is used more than once", probe.name));
+                }
+            }
+            self.expression.validate(&names)?;
+        }
+        if let Some(weighting) = &self.weighting {
+            let (min, max) = (weighting.min_weight, weighting.max_weight);
+            if !(1..=255).contains(&min) || !(1..=255).contains(&max) || min > max {
+                return Err(format!("weighting needs 1 <= min_weight <= max_weight <= 255, got {} and {}", min, max));
+            }
+        }
+        Ok(())
+    }
+
+    // ports, or a single mapping for `port`, probed on the Service's
+    // targetPort if there is one.
+    pub fn port_mappings(&self) -> Vec<PortMapping> {
+        match &self.ports {
+            Some(ports) if !ports.is_empty() => ports.clone(),
+            _ => vec![PortMapping { probe_port: None, nodebalancer_port: self.port }],
+        }
+    }
+}
+
+// Probe targets on probe_port and change the NodeBalancer config on
+// nodebalancer_port. Without probe_port the Service's targetPort is used,
+// or nodebalancer_port.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+pub struct PortMapping {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe_port: Option<PortRef>,
+    pub nodebalancer_port: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+pub struct NamedProbe {
+    pub name: String,
+    pub port: PortRef,
+}
+
+// How probe results combine: `all`, `any`, `atLeast: N`, or a tree of
+// `and`/`or` over `probe: name` leaves.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ProbeExpression {
+    #[default]
+    All,
+    Any,
+    AtLeast(usize),
+    And(Vec<ProbeExpression>),
+    Or(Vec<ProbeExpression>),
+    Probe(String),
+}
+
+impl ProbeExpression {
+    // Every atLeast has to be reachable and every probe leaf has to name one
+    // of the probes.
+    pub fn validate(&self, names: &BTreeSet<&str>) -> Result<(), String> {
+        match self {
+            ProbeExpression::All | ProbeExpression::Any => Ok(()),
+            ProbeExpression::AtLeast(n) if (1..=names.len()).contains(n) => Ok(()),
+            ProbeExpression::AtLeast(n) => Err(format!("atLeast {} needs to be between 1 and the {} probes", n, names.len())),
+            ProbeExpression::And(terms) | ProbeExpression::Or(terms) => terms.iter().try_for_each(|term| term.validate(names)),
+            ProbeExpression::Probe(name) if names.contains(name.as_str()) => Ok(()),
+            ProbeExpression::Probe(name) => Err(format!("expression names unknown probe {}", name)),
+        }
+    }
+
+    pub fn evaluate(&self, results: &BTreeMap<String, bool>) -> Result<bool, String> {
+        match self {
+            ProbeExpression::All => Ok(results.values().all(|passed| *passed)),
+            ProbeExpression::Any => Ok(results.values().any(|passed| *passed)),
+            ProbeExpression::AtLeast(n) => Ok(results.values().filter(|passed| **passed).count() >= *n),
+            ProbeExpression::And(terms) => {
+                for term in terms {
+                    if !term.evaluate(results)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            ProbeExpression::Or(terms) => {
+                for term in terms {
+                    if term.evaluate(results)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            ProbeExpression::Probe(name) => results.get(name).copied().ok_or(format!("expression names unknown probe {}", name)),
+        }
+    }
+}
+
+// A port number, or the name of a container port on the target pod.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(untagged)]
+pub enum PortRef {
+    Number(i32),
+    Name(String),
+}
+
+// NodeBalancers the HealthCheck may change. Without a scope every
+// NodeBalancer config on `port` with a node on the node's address is used.
+// NodeBalancers named directly and the one behind `service` are combined.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+pub struct NodeBalancerScope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodebalancer_ids: Option<Vec<i32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_ids: Option<Vec<i32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<ServiceReference>,
+}
+
+// A LoadBalancer Service, in serv_namespace unless namespace is set.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+pub struct ServiceReference {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+// What gets probed on `port`: each pod on the node, or the node's own
+// InternalIP for NodePort and hostNetwork backends.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ProbeTarget {
+    #[default]
+    PodIP,
+    NodeIP,
+}
+
+// How a target is judged: a TCP connect from the operator, or the pod's
+// readiness as reported by the kubelet. podReadiness needs target podIP.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ProbeSource {
+    #[default]
+    Tcp,
+    PodReadiness,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum NoPodsPolicy {
+    #[default]
+    Ignore,
+    Healthy,
+    Unhealthy,
+}
+
+// Scale NodeBalancer weight down for nodes whose smoothed probe latency is
+// above target_latency_ms, between min_weight and max_weight.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+pub struct Weighting {
+    pub target_latency_ms: u64,
+    #[serde(default = "default_min_weight")]
+    pub min_weight: i32,
+    #[serde(default = "default_max_weight")]
+    pub max_weight: i32,
+    // Weight of the newest sample in the latency moving average, 0 to 1.
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f64,
+    // Smallest weight change worth sending to the NodeBalancer.
+    #[serde(default = "default_min_step")]
+    pub min_step: i32,
+}
+
+fn default_min_weight() -> i32 {
+    10
+}
+
+fn default_max_weight() -> i32 {
+    100
+}
+
+fn default_smoothing() -> f64 {
+    0.3
+}
+
+fn default_min_step() -> i32 {
+    5
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DrainEscalation {
+    #[default]
+    Reject,
+    Backup,
+}
+
+impl DrainEscalation {
+    pub fn mode(&self) -> &'static str {
+        match self {
+            DrainEscalation::Reject => "reject",
+            DrainEscalation::Backup => "backup",
+        }
+    }
+}
+
+// Either a cron schedule (UTC, "min hour dom month dow") with a duration in
+// seconds, or an absolute start/end range as RFC3339 timestamps.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+pub struct MaintenanceWindow {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default, JsonSchema)]
+pub struct HealthCheckStatus {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<BTreeMap<String, NodeHealthStatus>>,
+}
+
+// Last NodeBalancer mode set for a node by this HealthCheck and why.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+pub struct NodeHealthStatus {
+    pub mode: String,
+    pub reason: String,
+    pub last_transition: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn spec(extra: serde_json::Value) -> HealthCheckSpec {
+        let mut spec = json!({ "timeout": 5, "port": 80, "serv_namespace": "default" });
+        spec.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(spec).unwrap()
+    }
+
+    #[test]
+    fn validate_accepts_defaults() {
+        assert_eq!(spec(json!({})).validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_needs_a_port() {
+        let mut spec = spec(json!({ "ports": [] }));
+        spec.port = 0;
+        assert!(spec.validate().is_err());
+        spec.ports = None;
+        assert!(spec.validate().is_err());
+        spec.ports = Some(vec![PortMapping { probe_port: None, nodebalancer_port: 443 }]);
+        assert_eq!(spec.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_node_ip_combinations() {
+        assert!(spec(json!({ "target": "nodeIP", "source": "podReadiness" })).validate().is_err());
+        assert!(spec(json!({ "target": "nodeIP", "service": "web" })).validate().is_err());
+        assert_eq!(spec(json!({ "target": "podIP", "service": "web" })).validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_probes() {
+        let probes = json!([{ "name": "http", "port": 80 }, { "name": "admin", "port": "admin" }]);
+        assert_eq!(spec(json!({ "probes": probes, "expression": { "atLeast": 2 } })).validate(), Ok(()));
+        assert!(spec(json!({ "probes": probes, "expression": { "atLeast": 0 } })).validate().is_err());
+        assert!(spec(json!({ "probes": probes, "expression": { "atLeast": 3 } })).validate().is_err());
+        assert!(spec(json!({ "probes": probes, "expression": { "or": [{ "probe": "http" }, { "atLeast": 3 }] } })).validate().is_err());
+        assert!(spec(json!({ "probes": probes, "expression": { "probe": "grpc" } })).validate().is_err());
+        let duplicate = json!([{ "name": "http", "port": 80 }, { "name": "http", "port": 8080 }]);
+        assert!(spec(json!({ "probes": duplicate })).validate().is_err());
+    }
+
+    fn results(passed: &[(&str, bool)]) -> BTreeMap<String, bool> {
+        passed.iter().map(|(name, passed)| (name.to_string(), *passed)).collect()
+    }
+
+    #[test]
+    fn evaluate_all_any_at_least() {
+        let results = results(&[("a", true), ("b", false), ("c", true)]);
+        assert_eq!(ProbeExpression::All.evaluate(&results), Ok(false));
+        assert_eq!(ProbeExpression::Any.evaluate(&results), Ok(true));
+        assert_eq!(ProbeExpression::AtLeast(2).evaluate(&results), Ok(true));
+        assert_eq!(ProbeExpression::AtLeast(3).evaluate(&results), Ok(false));
+    }
+
+    #[test]
+    fn evaluate_tree() {
+        let results = results(&[("http", true), ("grpc", false), ("admin", true)]);
+        let probe = |name: &str| ProbeExpression::Probe(name.to_string());
+        let expression = ProbeExpression::And(vec![probe("http"), ProbeExpression::Or(vec![probe("grpc"), probe("admin")])]);
+        assert_eq!(expression.evaluate(&results), Ok(true));
+        let expression = ProbeExpression::And(vec![probe("http"), probe("grpc")]);
+        assert_eq!(expression.evaluate(&results), Ok(false));
+        assert!(probe("missing").evaluate(&results).is_err());
+    }
+
+    #[test]
+    fn validate_weighting_bounds() {
+        let weighting = |min: i32, max: i32| spec(json!({ "weighting": { "target_latency_ms": 100, "min_weight": min, "max_weight": max } }));
+        assert_eq!(weighting(1, 255).validate(), Ok(()));
+        assert_eq!(weighting(50, 50).validate(), Ok(()));
+        assert!(weighting(60, 50).validate().is_err());
+        assert!(weighting(0, 50).validate().is_err());
+        assert!(weighting(10, 256).validate().is_err());
+    }
 }
@@ -1,17 +1,36 @@
-use tokio_postgres::{Row, Client, Error};
+use tokio_postgres::{Row, Client};
 use std::collections::HashMap;
 use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
 use postgres_openssl::MakeTlsConnector;
 use std::env;
 use serde::{Serialize};
 use std::sync::LazyLock;
+use crate::store::{AuditQuery, AuditRecord, StateUpdate};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("TLS setup failed: {0}")]
+    Tls(#[from] openssl::error::ErrorStack),
+    #[error("CERTLOCATION not set")]
+    MissingCertLocation,
+}
 
 
-static maindb_pw: LazyLock<String> = std::sync::LazyLock::new(|| { env::var("MAINDB_PASSWORD").expect("MAINDB_PASSWORD not set!") });
 static localdb_pw: LazyLock<String> = std::sync::LazyLock::new(|| { env::var("LOCALDB_PASSWORD").expect("LOCALDB_PASSWORD not set!") });
-static maindb_hostport: LazyLock<String> = std::sync::LazyLock::new(|| { env::var("MAINDB_HOSTPORT").expect("MAINDB_HOSTPORT not set!") });
 static localdb_hostport: LazyLock<String> = std::sync::LazyLock::new(|| { env::var("LOCALDB_HOSTPORT").expect("LOCALDB_HOSTPORT not set!") });
 
+// Checked at startup when Postgres is the state store, see hcapi::check_config.
+pub fn check_config() -> Result<(), String> {
+    for name in ["LOCALDB_PASSWORD", "LOCALDB_HOSTPORT", "CERTLOCATION"] {
+        if env::var(name).is_err() {
+            return Err(format!("{} not set", name));
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct Nodebalancer {
     _id: i32,
@@ -19,22 +38,24 @@ pub struct Nodebalancer {
     port: i32,
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
+#[derive(serde::Deserialize, Serialize, Debug, Default)]
+#[serde(default)]
 pub struct NodeBalancerListObject {
     client_conn_throttle: i32,
     created: String,
     hostname: String,
     pub id: i32,
-    ipv4: String,
-    ipv6: String,
+    pub ipv4: String,
+    ipv6: Option<String>,
     label: String,
-    lke_cluster: Option<LkeCluster>,
-    region: String,
+    pub lke_cluster: Option<LkeCluster>,
+    pub region: String,
+    pub tags: Vec<String>,
     r#type: String,
     updated: String,
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
+#[derive(serde::Deserialize, Serialize, Debug, Clone)]
 pub struct LocalNodeBalancerListObject {
     pub nb_id: i32,
     pub ipv4: String,
@@ -42,21 +63,22 @@ pub struct LocalNodeBalancerListObject {
     pub lke_id: i32,
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
+#[derive(serde::Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct NodeObject {
-    address: String,
-    config_id: i32,
-    id: i32,
+    pub address: String,
+    pub config_id: i32,
+    pub id: i32,
     label: String,
-    mode: String,
-    nodebalancer_id: i32,
-    status: String,
+    pub mode: String,
+    pub nodebalancer_id: i32,
+    pub status: String,
     weight: i32 
 }
 
 #[derive(serde::Deserialize, Serialize, Debug)]
 pub struct LkeCluster{
-    id: i32,
+    pub id: i32,
     label: String,
     r#type: String,
     url: String,
@@ -73,9 +95,10 @@ impl Default for LkeCluster {
     }
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
+#[derive(serde::Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct NodeBalancerConfigObject {
-    algorithm: String,
+    pub algorithm: String,
     check: String,
     check_attempts: i32,
     check_body: String,
@@ -86,8 +109,8 @@ pub struct NodeBalancerConfigObject {
     cipher_suite: String,
     pub id: i32,
     pub nodebalancer_id: i32,
-    nodes_status: NodeStatus,
-    port: i32,
+    pub nodes_status: NodeStatus,
+    pub port: i32,
     protocol: String,
     proxy_protocol: String,
     stickiness: String,
@@ -95,188 +118,230 @@ pub struct NodeBalancerConfigObject {
     udp_session_timeout: i32, 
 }
 
-#[derive(serde::Deserialize, Serialize, Debug)]
+#[derive(serde::Deserialize, Serialize, Debug, Clone, Default)]
 pub struct NodeStatus {
-    down: i32,
-    up: i32,
+    pub down: i32,
+    pub up: i32,
 }
 
-async fn create_connector() -> MakeTlsConnector {
-    let mut builder = SslConnector::builder(SslMethod::tls()).expect("unable to create sslconnector builder");
-    let cert_location = env::var("CERTLOCATION");
-    builder.set_ca_file(cert_location.unwrap()).expect("unable to load ca.cert");
+async fn create_connector() -> Result<MakeTlsConnector, Error> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    let cert_location = env::var("CERTLOCATION").map_err(|_| Error::MissingCertLocation)?;
+    builder.set_ca_file(cert_location)?;
     builder.set_verify(SslVerifyMode::NONE);
     let connector = MakeTlsConnector::new(builder.build());
 
-    connector
+    Ok(connector)
 } 
 
-pub async fn create_localdb_client() -> Client {
-    let connector = create_connector().await;
+pub async fn create_localdb_client() -> Result<Client, Error> {
+    let connector = create_connector().await?;
 
     let url = format!("postgresql://akmadmin:{}@{}/defaultdb", localdb_pw.to_string(), localdb_hostport.to_string());
-    //let Ok((client, connection)) = tokio_postgres::connect(&url, connector).await else { panic!("Client failure.") };
-    let Ok((client, connection)) = tokio_postgres::connect(&url, connector).await else { todo!() };
+    let (client, connection) = tokio_postgres::connect(&url, connector).await?;
     tokio::spawn(async move {
         if let Err(e) = connection.await {
             eprintln!("connection error: {}", e);
         }
     });
 
-    client 
+    Ok(client)
 
 }
 
-pub async fn create_maindb_client() -> Client {
-    let connector = create_connector().await;
-
-    let url = format!("postgresql://akmadmin:{}@{}/defaultdb", maindb_pw.to_string(), maindb_hostport.to_string());
-    let Ok((client, connection)) = tokio_postgres::connect(&url, connector).await else { panic!("Client failure.") };
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
-        }
-    });
-
-    client 
+// Schema changes made after the initial tables were created by hand. State
+// kept per (healthcheck, node_name, node_id) lives in its own tables; the
+// original state and shadow_state tables are left as they are, since other
+// clusters and older operators may share this database.
+pub async fn migrate() -> Result<(), Error> {
+    let connection = create_localdb_client().await?;
+    connection.batch_execute(
+        "CREATE TABLE IF NOT EXISTS healthcheck_state (
+            healthcheck TEXT NOT NULL,
+            node_name TEXT NOT NULL,
+            nodebalancer_id INTEGER NOT NULL,
+            nodebalancer_config_id INTEGER NOT NULL,
+            node_id INTEGER NOT NULL,
+            podip TEXT NOT NULL,
+            pod_uid TEXT NOT NULL DEFAULT '',
+            port INTEGER NOT NULL,
+            lastmode TEXT NOT NULL,
+            current TEXT NOT NULL,
+            cluster_name TEXT NOT NULL,
+            drain_started BIGINT,
+            latency_ms DOUBLE PRECISION,
+            weight INTEGER,
+            PRIMARY KEY (healthcheck, node_name, node_id)
+         );
+         CREATE TABLE IF NOT EXISTS healthcheck_shadow_state (LIKE healthcheck_state INCLUDING ALL);
+         CREATE INDEX IF NOT EXISTS healthcheck_state_cluster_node ON healthcheck_state (cluster_name, node_id);
+         CREATE TABLE IF NOT EXISTS mode_change_audit (
+            id BIGSERIAL PRIMARY KEY,
+            changed_at TIMESTAMPTZ NOT NULL,
+            cluster_name TEXT NOT NULL,
+            node_name TEXT NOT NULL,
+            podip TEXT NOT NULL,
+            nodebalancer_id INTEGER NOT NULL,
+            nodebalancer_config_id INTEGER NOT NULL,
+            node_id INTEGER NOT NULL,
+            old_mode TEXT NOT NULL,
+            new_mode TEXT NOT NULL,
+            healthcheck TEXT NOT NULL,
+            reason TEXT NOT NULL,
+            probe_type TEXT NOT NULL,
+            error TEXT,
+            latency_ms DOUBLE PRECISION,
+            api_success BOOLEAN NOT NULL
+         );
+         CREATE INDEX IF NOT EXISTS mode_change_audit_changed_at ON mode_change_audit (changed_at);",
+    ).await?;
 
+    Ok(())
 }
 
-pub async fn get_db_state(port: i32, podip: String, clustername: &String) -> Result<Vec<Row>, Error> {
-    let mut connection = create_localdb_client().await;
-    //let searchpattern = format!("%{}%", &podip);
-    let state_query = connection.query(
-            "SELECT * FROM state WHERE podip = $1 AND cluster_name = $2 AND port = $3",
-            &[&podip, &clustername, &port],
-    ).await;
+pub async fn get_db_state(healthcheck: &str, node_name: &str) -> Result<Vec<Row>, Error> {
+    let connection = create_localdb_client().await?;
+    Ok(connection.query(
+            "SELECT * FROM healthcheck_state WHERE healthcheck = $1 AND node_name = $2",
+            &[&healthcheck, &node_name],
+    ).await?)
+}
 
-    match state_query {
-        Ok(ref success) => (),
-        Err(ref e) => {
-            if e.to_string().contains("duplicate key value violates unique constraint") {
-                ();
-            } else {
-                println!("{:?}", e);
-            }
-        }
-    }
+pub async fn update_state(update: &StateUpdate) -> Result<(), Error> {
+    let connection = create_localdb_client().await?;
+    connection.execute(
+            "INSERT INTO healthcheck_state (healthcheck, node_name, nodebalancer_id, nodebalancer_config_id, node_id, podip, pod_uid, port, lastmode, current, cluster_name, drain_started) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, CASE WHEN $10 = 'drain' THEN extract(epoch FROM now())::bigint END) ON CONFLICT (healthcheck, node_name, node_id) DO UPDATE SET nodebalancer_config_id = EXCLUDED.nodebalancer_config_id, podip = EXCLUDED.podip, pod_uid = EXCLUDED.pod_uid, port = EXCLUDED.port, lastmode = EXCLUDED.lastmode, current = EXCLUDED.current, drain_started = CASE WHEN EXCLUDED.current = 'drain' THEN COALESCE(healthcheck_state.drain_started, EXCLUDED.drain_started) END;",
+            &[&update.healthcheck, &update.node_name, &update.nodebalancer_id, &update.nodebalancer_config_id, &update.node_id, &update.podip, &update.pod_uid, &update.port, &update.lastmode, &update.current, &update.cluster_name],
+    ).await?;
 
-    Ok(state_query?.clone())
+    Ok(())
 
+}
 
+pub async fn get_cluster_states(clustername: &String, shadow: bool) -> Result<Vec<Row>, Error> {
+    let connection = create_localdb_client().await?;
+    let table = if shadow { "healthcheck_shadow_state" } else { "healthcheck_state" };
+    Ok(connection.query(
+            &format!("SELECT * FROM {} WHERE cluster_name = $1", table),
+            &[&clustername],
+    ).await?)
 }
 
-pub async fn update_state(nbid: i32, nbcfgid: i32, nodeid: i32, podip: &String, port: i32, lastmode: String, current: String, clustername: &String) -> Result<(), Box<dyn std::error::Error>> {
-    let mut connection = create_localdb_client().await;
-    let update = connection.execute(
-            "INSERT INTO state (nodebalancer_id, nodebalancer_config_id, node_id, podip, port, lastmode, current, cluster_name) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (port, podip, cluster_name) DO UPDATE SET port = EXCLUDED.port, lastmode = EXCLUDED.lastmode, current = EXCLUDED.current;",
-            &[&nbid, &nbcfgid, &nodeid, &podip, &port, &lastmode.to_string(), &current.to_string(), &clustername],
-    ).await;
-
-    match update {
-        Ok(success) => println!("{:#?}", success),
-        Err(e) => {
-            if e.to_string().contains("duplicate key value violates unique constraint") {
-                //();
-                println!("{:?}", e);
-            } else {
-                println!("{:?}", e);
-            }
-        }
-    }
+pub async fn delete_state(healthcheck: &str, node_name: &str, node_id: i32, shadow: bool) -> Result<(), Error> {
+    let connection = create_localdb_client().await?;
+    let table = if shadow { "healthcheck_shadow_state" } else { "healthcheck_state" };
+    connection.execute(
+            &format!("DELETE FROM {} WHERE healthcheck = $1 AND node_name = $2 AND node_id = $3", table),
+            &[&healthcheck, &node_name, &node_id],
+    ).await?;
 
     Ok(())
 
 }
 
-pub async fn update_db_nb(nodebalancers: LocalNodeBalancerListObject) -> Result<(), Box<dyn std::error::Error>> {
-    let mut connection = create_localdb_client().await;
-    let update = connection.execute(
-            "INSERT INTO nodebalancer (nb_id, ipv4, region, lke_id) VALUES ($1, $2, $3, $4)",
-            &[&nodebalancers.nb_id, &nodebalancers.ipv4, &nodebalancers.region, &nodebalancers.lke_id],
-    ).await;
-
-    match update {
-        Ok(success) => (),
-        Err(e) => {
-            if e.to_string().contains("duplicate key value violates unique constraint") {
-                ();
-            } else {
-                println!("{:?}", e);
-            }
-        }
-    }
+// Every HealthCheck's row for one NodeBalancer node.
+pub async fn get_nb_node_states(node_id: i32, clustername: &str, shadow: bool) -> Result<Vec<Row>, Error> {
+    let connection = create_localdb_client().await?;
+    let table = if shadow { "healthcheck_shadow_state" } else { "healthcheck_state" };
+    Ok(connection.query(
+            &format!("SELECT * FROM {} WHERE node_id = $1 AND cluster_name = $2", table),
+            &[&node_id, &clustername],
+    ).await?)
+}
 
-    Ok(())
+// Decisions made in dry run, kept apart from the state the NodeBalancers are in.
+pub async fn get_shadow_state(healthcheck: &str, node_name: &str) -> Result<Vec<Row>, Error> {
+    let connection = create_localdb_client().await?;
+    Ok(connection.query(
+            "SELECT * FROM healthcheck_shadow_state WHERE healthcheck = $1 AND node_name = $2",
+            &[&healthcheck, &node_name],
+    ).await?)
 
 }
 
-pub async fn get_nb_ids() -> Result<Vec<Row>, Error> {
-    let mut node_connection = create_localdb_client().await;
-    let nb_table = node_connection.query(
-        "SELECT nb_id FROM nodebalancer", &[],
-    ).await;
+pub async fn update_shadow_state(update: &StateUpdate) -> Result<(), Error> {
+    let connection = create_localdb_client().await?;
+    connection.execute(
+            "INSERT INTO healthcheck_shadow_state (healthcheck, node_name, nodebalancer_id, nodebalancer_config_id, node_id, podip, pod_uid, port, lastmode, current, cluster_name, drain_started) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, CASE WHEN $10 = 'drain' THEN extract(epoch FROM now())::bigint END) ON CONFLICT (healthcheck, node_name, node_id) DO UPDATE SET nodebalancer_config_id = EXCLUDED.nodebalancer_config_id, podip = EXCLUDED.podip, pod_uid = EXCLUDED.pod_uid, port = EXCLUDED.port, lastmode = EXCLUDED.lastmode, current = EXCLUDED.current, drain_started = CASE WHEN EXCLUDED.current = 'drain' THEN COALESCE(healthcheck_shadow_state.drain_started, EXCLUDED.drain_started) END;",
+            &[&update.healthcheck, &update.node_name, &update.nodebalancer_id, &update.nodebalancer_config_id, &update.node_id, &update.podip, &update.pod_uid, &update.port, &update.lastmode, &update.current, &update.cluster_name],
+    ).await?;
 
-    Ok(nb_table?)
+    Ok(())
 
 }
 
-pub async fn get_nb_by_loc(loc: String) -> Result<Vec<Row>, Error> {
-    let mut node_connection = create_maindb_client().await;
-    let nb_table = node_connection.query(
-        "SELECT * FROM nodebalancer where region = $1", &[&loc],
-    ).await;
+pub async fn update_weight_state(healthcheck: &str, node_name: &str, node_id: i32, latency_ms: f64, weight: i32) -> Result<(), Error> {
+    let connection = create_localdb_client().await?;
+    connection.execute(
+            "UPDATE healthcheck_state SET latency_ms = $1, weight = $2 WHERE healthcheck = $3 AND node_name = $4 AND node_id = $5",
+            &[&latency_ms, &weight, &healthcheck, &node_name, &node_id],
+    ).await?;
 
-    Ok(nb_table?)
+    Ok(())
 
 }
 
-pub async fn get_nbcfg_ids() -> Result<Vec<Row>, Error> {
-    let mut node_connection = create_localdb_client().await;
-    let nb_table = node_connection.query(
-        "SELECT id, nodebalancer_id FROM nodebalancer_config", &[],
-    ).await;
+pub async fn insert_audit(record: &AuditRecord) -> Result<(), Error> {
+    let connection = create_localdb_client().await?;
+    connection.execute(
+            "INSERT INTO mode_change_audit (changed_at, cluster_name, node_name, podip, nodebalancer_id, nodebalancer_config_id, node_id, old_mode, new_mode, healthcheck, reason, probe_type, error, latency_ms, api_success) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)",
+            &[&record.changed_at, &record.cluster_name, &record.node_name, &record.podip, &record.nodebalancer_id, &record.nodebalancer_config_id, &record.node_id, &record.old_mode, &record.new_mode, &record.healthcheck, &record.reason, &record.probe_type, &record.error, &record.latency_ms, &record.api_success],
+    ).await?;
 
-    Ok(nb_table?)
+    Ok(())
 
 }
 
-pub async fn get_by_node_ip_nbcfg(ip: &String, port: &i32) -> Result<Vec<Row>, Error> {
-    //let mut new_ip: &String = ip;
-    let searchpattern = format!("%{}%", &ip);
-    let mut node_connection = create_localdb_client().await;
-    let nb_table = node_connection.query(
-        "select * from node INNER JOIN nodebalancer_config ON node.config_id = nodebalancer_config.id where address LIKE $1 AND port = $2;", &[&searchpattern, &port],
+pub async fn get_audit(query: &AuditQuery) -> Result<Vec<Row>, Error> {
+    let connection = create_localdb_client().await?;
+    Ok(connection.query(
+            "SELECT * FROM mode_change_audit WHERE ($1::text IS NULL OR node_name = $1) AND ($2::text IS NULL OR healthcheck = $2) AND ($3::timestamptz IS NULL OR changed_at >= $3) ORDER BY id DESC LIMIT $4",
+            &[&query.node_name, &query.healthcheck, &query.since, &query.limit],
+    ).await?)
+}
+
+pub async fn update_db_nb(nodebalancers: LocalNodeBalancerListObject) -> Result<(), Box<dyn std::error::Error>> {
+    let connection = create_localdb_client().await?;
+    let update = connection.execute(
+            "INSERT INTO nodebalancer (nb_id, ipv4, region, lke_id) VALUES ($1, $2, $3, $4)",
+            &[&nodebalancers.nb_id, &nodebalancers.ipv4, &nodebalancers.region, &nodebalancers.lke_id],
     ).await;
 
-    match nb_table {
-        Ok(ref success) => (),
-        Err(ref e) => {
+    match update {
+        Ok(success) => (),
+        Err(e) => {
             if e.to_string().contains("duplicate key value violates unique constraint") {
-                println!("{:?}", e);
+                ();
             } else {
                 println!("{:?}", e);
             }
         }
     }
 
-    Ok(nb_table?)
+    Ok(())
 
 }
 
-pub async fn get_by_node_ip_nb(ip: String) -> Result<Vec<Row>, Error> {
-    //FIX the same as function above
-    let mut node_connection = create_localdb_client().await;
-    let nb_table = node_connection.query(
-        "select * from node INNER JOIN nodebalancer ON node.nodebalancer_id = nodebalancer.nb_id where address like '%$1%';", &[&ip],
-    ).await;
+// prefix is the node address up to the port, see store::address_prefix.
+// nbids and cfgids restrict the result when set, see store::NbScope.
+pub async fn get_by_node_ip_nbcfg(prefix: &str, port: &i32, nbids: &Option<Vec<i32>>, cfgids: &Option<Vec<i32>>) -> Result<Vec<Row>, Error> {
+    let searchpattern = format!("{}%", prefix);
+    let node_connection = create_localdb_client().await?;
+    Ok(node_connection.query(
+        "select * from node INNER JOIN nodebalancer_config ON node.config_id = nodebalancer_config.id where address LIKE $1 AND port = $2
+         AND ($3::int[] IS NULL OR node.nodebalancer_id = ANY($3)) AND ($4::int[] IS NULL OR node.config_id = ANY($4));",
+        &[&searchpattern, &port, nbids, cfgids],
+    ).await?)
 
-    Ok(nb_table?)
+}
 
+pub async fn get_nb_by_ip(ip: &str) -> Result<Vec<Row>, Error> {
+    let connection = create_localdb_client().await?;
+    Ok(connection.query("SELECT nb_id FROM nodebalancer WHERE ipv4 = $1", &[&ip]).await?)
 }
 
 pub async fn update_db_node(node: NodeObject) -> Result<(), Box<dyn std::error::Error>> {
-    let mut node_connection = create_localdb_client().await;
+    let node_connection = create_localdb_client().await?;
     let nb_table = node_connection.execute(
             "INSERT INTO node (id, address, status, config_id, nodebalancer_id) VALUES ($1, $2, $3, $4, $5)",
             &[&node.id, &node.address, &node.status, &node.config_id, &node.nodebalancer_id],
@@ -285,8 +350,16 @@ pub async fn update_db_node(node: NodeObject) -> Result<(), Box<dyn std::error::
     Ok(())
 }
 
+pub async fn prune_inventory(nbids: &[i32], cfgids: &[i32], nodeids: &[i32]) -> Result<(), Error> {
+    let connection = create_localdb_client().await?;
+    connection.execute("DELETE FROM nodebalancer WHERE NOT (nb_id = ANY($1))", &[&nbids]).await?;
+    connection.execute("DELETE FROM nodebalancer_config WHERE NOT (id = ANY($1))", &[&cfgids]).await?;
+    connection.execute("DELETE FROM node WHERE NOT (id = ANY($1))", &[&nodeids]).await?;
+    Ok(())
+}
+
 pub async fn update_db_config(nodebalancer_config: NodeBalancerConfigObject) -> Result<(), Box<dyn std::error::Error>> {
-    let mut config_connection = create_localdb_client().await;
+    let config_connection = create_localdb_client().await?;
     let nb_cfg_table = config_connection.execute(
             "INSERT INTO nodebalancer_config (id, algorithm, port, up, down, nodebalancer_id) VALUES ($1, $2, $3, $4, $5, $6)",
             &[&nodebalancer_config.id, &nodebalancer_config.algorithm, &nodebalancer_config.port, &nodebalancer_config.nodes_status.up, &nodebalancer_config.nodes_status.down, &nodebalancer_config.nodebalancer_id],
@@ -5,6 +5,8 @@ use reqwest::Client;
 use std::env;
 use std::sync::LazyLock;
 use std::collections::HashMap;
+use crate::database::{NodeBalancerConfigObject, NodeBalancerListObject, NodeObject};
+use serde::de::DeserializeOwned;
 
 
 static api_version: LazyLock<String> = LazyLock::new(|| {
@@ -14,13 +16,24 @@ static token: LazyLock<String> = LazyLock::new(|| {
     env::var("TOKEN").expect("TOKEN not set!")
 });
 
+// Checked at startup, so a missing setting stops the operator there rather
+// than panicking in the middle of a reconcile.
+pub fn check_config() -> Result<(), String> {
+    for name in ["APIVERSION", "TOKEN"] {
+        if env::var(name).is_err() {
+            return Err(format!("{} not set", name));
+        }
+    }
+    Ok(())
+}
+
 
 
 pub async fn change_node_mode(nbid: &i32, configid: &i32, nodeid: &i32, nodemode: String) -> Result<(), Box<dyn std::error::Error>> {
     println!("####################################CHANGING STATE {}################################", nodemode);
     let auth_header = format!("Bearer {}", token.to_string());
     let mut headers = HeaderMap::new();
-    headers.insert(AUTHORIZATION, HeaderValue::from_str(&auth_header).unwrap());
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(&auth_header)?);
     headers.insert("accept", HeaderValue::from_static("application/json"));
 
     let mut params = HashMap::new();
@@ -28,15 +41,133 @@ pub async fn change_node_mode(nbid: &i32, configid: &i32, nodeid: &i32, nodemode
 
     let client = reqwest::Client::builder()
         .default_headers(headers)
-        .build()
-        .unwrap();
+        .build()?;
 
     let url = format!("https://api.linode.com/{}/nodebalancers/{}/configs/{}/nodes/{}", api_version.to_string(), nbid, configid, nodeid);
-    let config_response = client
+    client
         .put(url)
         .json(&params)
         .send()
-        .await?;
+        .await?
+        .error_for_status()?;
 
     Ok(())
 }
+
+pub async fn change_node_weight(nbid: &i32, configid: &i32, nodeid: &i32, weight: i32) -> Result<(), Box<dyn std::error::Error>> {
+    let auth_header = format!("Bearer {}", *token);
+    let mut headers = HeaderMap::new();
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(&auth_header)?);
+    headers.insert("accept", HeaderValue::from_static("application/json"));
+
+    let mut params = HashMap::new();
+    params.insert("weight", weight);
+
+    let client = reqwest::Client::builder()
+        .default_headers(headers)
+        .build()?;
+
+    let url = format!("https://api.linode.com/{}/nodebalancers/{}/configs/{}/nodes/{}", *api_version, nbid, configid, nodeid);
+    client
+        .put(url)
+        .json(&params)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+pub async fn get_node(nbid: &i32, configid: &i32, nodeid: &i32) -> Result<NodeObject, Box<dyn std::error::Error>> {
+    let auth_header = format!("Bearer {}", *token);
+    let mut headers = HeaderMap::new();
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(&auth_header)?);
+    headers.insert("accept", HeaderValue::from_static("application/json"));
+
+    let client = reqwest::Client::builder()
+        .default_headers(headers)
+        .build()?;
+
+    let url = format!("https://api.linode.com/{}/nodebalancers/{}/configs/{}/nodes/{}", *api_version, nbid, configid, nodeid);
+    let node = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<NodeObject>()
+        .await?;
+
+    Ok(node)
+}
+
+pub async fn get_nodebalancer(nbid: &i32) -> Result<NodeBalancerListObject, Box<dyn std::error::Error>> {
+    let auth_header = format!("Bearer {}", *token);
+    let mut headers = HeaderMap::new();
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(&auth_header)?);
+    headers.insert("accept", HeaderValue::from_static("application/json"));
+
+    let client = reqwest::Client::builder()
+        .default_headers(headers)
+        .build()?;
+
+    let url = format!("https://api.linode.com/{}/nodebalancers/{}", *api_version, nbid);
+    let nodebalancer = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<NodeBalancerListObject>()
+        .await?;
+
+    Ok(nodebalancer)
+}
+
+#[derive(serde::Deserialize)]
+struct Page<T> {
+    data: Vec<T>,
+    page: i32,
+    pages: i32,
+}
+
+async fn get_all_pages<T: DeserializeOwned>(path: String) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+    let auth_header = format!("Bearer {}", *token);
+    let mut headers = HeaderMap::new();
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(&auth_header)?);
+    headers.insert("accept", HeaderValue::from_static("application/json"));
+
+    let client = reqwest::Client::builder()
+        .default_headers(headers)
+        .build()?;
+
+    let mut items = Vec::new();
+    let mut page = 1;
+    loop {
+        let url = format!("https://api.linode.com/{}/{}?page={}&page_size=500", *api_version, path, page);
+        let response = client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Page<T>>()
+            .await?;
+        items.extend(response.data);
+        if response.page >= response.pages {
+            break;
+        }
+        page += 1;
+    }
+
+    Ok(items)
+}
+
+pub async fn list_nodebalancers() -> Result<Vec<NodeBalancerListObject>, Box<dyn std::error::Error>> {
+    get_all_pages("nodebalancers".to_string()).await
+}
+
+pub async fn list_configs(nbid: &i32) -> Result<Vec<NodeBalancerConfigObject>, Box<dyn std::error::Error>> {
+    get_all_pages(format!("nodebalancers/{}/configs", nbid)).await
+}
+
+pub async fn list_nodes(nbid: &i32, configid: &i32) -> Result<Vec<NodeObject>, Box<dyn std::error::Error>> {
+    get_all_pages(format!("nodebalancers/{}/configs/{}/nodes", nbid, configid)).await
+}
@@ -1,35 +1,92 @@
 use std::sync::Arc;
 use futures::{StreamExt};
-use kube::runtime::{watcher::Config};
+use kube::runtime::{reflector, watcher, watcher::Config, WatchStreamExt};
+use kube::runtime::reflector::ObjectRef;
+use kube::runtime::events::Recorder;
 use kube::Resource;
 use kube::ResourceExt;
 use kube::{client::Client, runtime::controller::Action, runtime::Controller, Api};
-use k8s_openapi::api::core::v1::{Node};
-use tokio::time::Duration;
-use crate::crd::HealthCheck;
+use k8s_openapi::api::core::v1::{Namespace, Node, Pod};
+use tokio::time::{Duration, Instant};
+use crate::crd::{HealthCheck, NoPodsPolicy, ProbeSource, ProbeTarget};
 use futures::future::FutureExt;
 use kube::api::ListParams;
 use std::collections::BTreeMap;
-use crate::database::{
-    get_by_node_ip_nbcfg,
-    get_db_state,
-};
+use std::env;
+use std::sync::LazyLock;
+use crate::store::store;
 
 pub mod crd;
 mod actions;
 mod hcapi;
 mod database;
+mod maintenance;
+mod metrics;
+mod store;
+
+// Observe-only mode for every HealthCheck, see also HealthCheckSpec.dry_run.
+static DRY_RUN: LazyLock<bool> = LazyLock::new(|| env::var("DRY_RUN").is_ok_and(|v| v == "true"));
+// "correct" puts drifted NodeBalancer nodes back, "record" updates the state table instead.
+static DRIFT_POLICY: LazyLock<String> = LazyLock::new(|| env::var("DRIFT_POLICY").unwrap_or("correct".to_string()));
+static DRIFT_INTERVAL: LazyLock<u64> = LazyLock::new(|| env::var("DRIFT_INTERVAL").ok().and_then(|v| v.parse().ok()).unwrap_or(300));
+static INVENTORY_INTERVAL: LazyLock<u64> = LazyLock::new(|| env::var("INVENTORY_INTERVAL").ok().and_then(|v| v.parse().ok()).unwrap_or(300));
+static GC_INTERVAL: LazyLock<u64> = LazyLock::new(|| env::var("GC_INTERVAL").ok().and_then(|v| v.parse().ok()).unwrap_or(300));
+static CLUSTER_NAME: LazyLock<Option<String>> = LazyLock::new(|| env::var("CLUSTER_NAME").ok().filter(|v| !v.is_empty()));
+static CLUSTER_NAME_LABEL: LazyLock<String> = LazyLock::new(|| env::var("CLUSTER_NAME_LABEL").unwrap_or("lke.linode.com/cluster-id".to_string()));
+const CAPI_CLUSTER_ANNOTATION: &str = "cluster.x-k8s.io/cluster-name";
+// Label selector for the pod watch, to leave out pods no HealthCheck probes.
+static POD_WATCH_SELECTOR: LazyLock<Option<String>> = LazyLock::new(|| env::var("POD_WATCH_SELECTOR").ok().filter(|v| !v.is_empty()));
+static METRICS_ADDR: LazyLock<String> = LazyLock::new(|| env::var("METRICS_ADDR").unwrap_or("0.0.0.0:9090".to_string()));
 
 #[tokio::main]
 async fn main() {
     let kubernetes_client: Client = Client::try_default()
         .await
         .expect("Expected a valid KUBECONFIG environment variable.");
+    if let Err(e) = store::check_config() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    store::init(kubernetes_client.clone());
+
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("audit") {
+        if let Err(e) = print_audit(&args[2..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
+    if let Err(e) = hcapi::check_config() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
     let node_api: Api<Node> = Api::all(kubernetes_client.clone());
-    let context: Arc<ContextData> = Arc::new(ContextData::new(kubernetes_client.clone()));
+    tokio::spawn(metrics::serve(METRICS_ADDR.to_string()));
+    if *DRY_RUN {
+        println!("DRY_RUN set - NodeBalancers will not be changed");
+    }
+    if let Err(e) = store().migrate().await {
+        eprintln!("State DB migration failed: {:?}", e);
+    }
+    if store::needs_inventory_sync() {
+        // Nodes can't be matched to NodeBalancers until the inventory is filled in.
+        actions::sync_inventory().await;
+        tokio::spawn(inventory_sync());
+    }
     let nodes = node_api.list(&Default::default()).await.unwrap();
     println!("Active nodes at start: {}", nodes.items.len());
+    let cluster_name = match resolve_cluster_name(kubernetes_client.clone(), &nodes.items).await {
+        Ok(cluster_name) => cluster_name,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let context: Arc<ContextData> = Arc::new(ContextData::new(kubernetes_client.clone(), cluster_name.clone()));
+    tokio::spawn(drift_check(kubernetes_client.clone(), context.recorder.clone(), cluster_name.clone()));
+    tokio::spawn(state_gc(kubernetes_client.clone(), cluster_name));
     let mut result = true;
     for node in nodes.items {
         if let Some(annotations) = &node.metadata.annotations {
@@ -42,7 +99,20 @@ async fn main() {
         }
     }
 
+    // HealthChecks are cached so pod events can be limited to the namespaces they target.
+    let (healthchecks, writer) = reflector::store::<HealthCheck>();
+    let hc_stream = reflector(writer, watcher(Api::<HealthCheck>::namespaced(kubernetes_client.clone(), "default"), Config::default()));
+    tokio::spawn(hc_stream.default_backoff().touched_objects().for_each(|_| futures::future::ready(())));
+
+    // The pod watch is cluster-wide because the namespaces HealthChecks target
+    // change at runtime and the controller takes one watch per kind. Pods are
+    // not cached, only mapped to their node, and pod_node drops the others.
+    let pod_watch = match POD_WATCH_SELECTOR.as_deref() {
+        Some(selector) => Config::default().labels(selector),
+        None => Config::default(),
+    };
     Controller::new(node_api.clone(), Config::default())
+        .watches(Api::<Pod>::all(kubernetes_client.clone()), pod_watch, move |pod| pod_node(&healthchecks, &pod))
         .graceful_shutdown_on(tokio::signal::ctrl_c().map(|_| ()))
         .run(reconcile, on_error, context)
         .for_each(|reconciliation_result| async move {
@@ -59,13 +129,90 @@ async fn main() {
         .await;
 }
 
+// `hc-operator audit [--node NAME] [--healthcheck NAME] [--since RFC3339] [--limit N] [--json]`
+// prints the mode change audit log, newest first.
+async fn print_audit(args: &[String]) -> Result<(), String> {
+    let mut query = store::AuditQuery { limit: 50, ..Default::default() };
+    let mut json = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--node" => query.node_name = Some(value()?),
+            "--healthcheck" => query.healthcheck = Some(value()?),
+            "--since" => {
+                let since = chrono::DateTime::parse_from_rfc3339(&value()?).map_err(|e| format!("invalid --since: {}", e))?;
+                query.since = Some(since.with_timezone(&chrono::Utc));
+            }
+            "--limit" => query.limit = value()?.parse().map_err(|e| format!("invalid --limit: {}", e))?,
+            "--json" => json = true,
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+    let records = store().get_audit(&query).await.map_err(|e| e.to_string())?;
+    for record in records {
+        if json {
+            println!("{}", serde_json::to_string(&record).map_err(|e| e.to_string())?);
+            continue;
+        }
+        println!(
+            "{} {} node={} pod={} nb={} config={} nbnode={} {} -> {} hc={} reason={} probe={} latency_ms={} api_success={} error={}",
+            record.changed_at.to_rfc3339(),
+            record.cluster_name,
+            record.node_name,
+            record.podip,
+            record.nodebalancer_id,
+            record.nodebalancer_config_id,
+            record.node_id,
+            if record.old_mode.is_empty() { "-" } else { &record.old_mode },
+            record.new_mode,
+            record.healthcheck,
+            record.reason,
+            record.probe_type,
+            record.latency_ms.map_or("-".to_string(), |l| format!("{:.1}", l)),
+            record.api_success,
+            record.error.as_deref().unwrap_or("-"),
+        );
+    }
+    Ok(())
+}
+
+// Runs once at startup and then every DRIFT_INTERVAL seconds.
+async fn drift_check(client: Client, recorder: Recorder, cluster_name: String) {
+    let correct = *DRIFT_POLICY != "record" && !*DRY_RUN;
+    loop {
+        actions::check_drift(client.clone(), &recorder, &cluster_name, correct).await;
+        tokio::time::sleep(Duration::from_secs(*DRIFT_INTERVAL)).await;
+    }
+}
+
+// Runs every GC_INTERVAL seconds, starting one interval after startup so
+// the first reconciles have written fresh state.
+async fn state_gc(client: Client, cluster_name: String) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(*GC_INTERVAL)).await;
+        actions::collect_garbage(client.clone(), &cluster_name).await;
+    }
+}
+
+// Runs every INVENTORY_INTERVAL seconds after the sync at startup.
+async fn inventory_sync() {
+    loop {
+        tokio::time::sleep(Duration::from_secs(*INVENTORY_INTERVAL)).await;
+        actions::sync_inventory().await;
+    }
+}
+
 struct ContextData {
     client: Client,
+    recorder: Recorder,
+    cluster_name: String,
 }
 
 impl ContextData {
-    pub fn new(client: Client) -> Self {
-        ContextData { client }
+    pub fn new(client: Client, cluster_name: String) -> Self {
+        let recorder = Recorder::new(client.clone(), "node-health-check-operator-rs".into());
+        ContextData { client, recorder, cluster_name }
     }
 }
 
@@ -75,73 +222,63 @@ enum HealthCheckAction {
     NoOp,
 }
 
-pub async fn get_lke_id(node: Arc<Node>) -> String {
-    //CLean this up from an error detection and handling
-    let annotations: Option<&BTreeMap<String, String>> = node.metadata.annotations.as_ref();
-    let annotation_key = "cluster.x-k8s.io/cluster-name";
-    let Some(annotation_value) = annotations.expect("").get(annotation_key) else { todo!() };
-
-    annotation_value.to_string()
-
+// Name the state table is keyed by, from the first of: CLUSTER_NAME, the
+// CLUSTER_NAME_LABEL node label, the Cluster API cluster-name annotation and
+// the kube-system namespace UID, which every cluster has.
+async fn resolve_cluster_name(client: Client, nodes: &[Node]) -> Result<String, Error> {
+    if let Some(name) = CLUSTER_NAME.as_ref() {
+        println!("Cluster identity {} from CLUSTER_NAME", name);
+        return Ok(name.clone());
+    }
+    if let Some(name) = nodes.iter().find_map(|n| n.labels().get(CLUSTER_NAME_LABEL.as_str())) {
+        println!("Cluster identity {} from node label {}", name, *CLUSTER_NAME_LABEL);
+        return Ok(name.clone());
+    }
+    if let Some(name) = nodes.iter().find_map(|n| n.annotations().get(CAPI_CLUSTER_ANNOTATION)) {
+        println!("Cluster identity {} from node annotation {}", name, CAPI_CLUSTER_ANNOTATION);
+        return Ok(name.clone());
+    }
+    let namespace = Api::<Namespace>::all(client).get("kube-system").await?;
+    let uid = namespace
+        .uid()
+        .ok_or(Error::MissingClusterIdentity("kube-system namespace has no UID".to_string()))?;
+    println!("Cluster identity {} from kube-system namespace UID", uid);
+    Ok(uid)
 }
 
 async fn reconcile(node: Arc<Node>, context: Arc<ContextData>) -> Result<Action, Error> {
     //Changed namespace logic based on customer requirements. Maybe list valid namespaces as vec in CRD definitions? 
     let client: Client = context.client.clone();
     let hcapi: Api<HealthCheck> = Api::namespaced(client.clone(), "default");
-    let name = node.metadata.name.clone().expect("Cannot get node name.").to_string();
-    let cluster_name = get_lke_id(node.clone()).await;
+    let name = node.name_any();
     let lp = ListParams::default();
-    let healthchecks = hcapi.list(&lp).await.unwrap();
-    for hclist in healthchecks.items.clone() {
-        let hc = hcapi.get(&hclist.metadata.name.expect("HC lookup issue")).await.unwrap();
+    let healthchecks = hcapi.list(&lp).await?;
+    for hc in &healthchecks.items {
         println!("{}-{}-{}", hc.spec.serv_namespace, hc.spec.timeout, hc.spec.port);
     }
 
+    let node_override = actions::get_override(&node);
+    if node_override == Some(actions::NodeOverride::Ignore) {
+        println!("Node {:?} has override ignore - skipping", &name);
+        return Ok(Action::requeue(Duration::from_secs(10)))
+    }
+    let drain_trigger = actions::get_drain_trigger(&node);
+
     match determine_action(&node) {
         HealthCheckAction::Create => {
             for hclist in &healthchecks.items {
-                let hc = hcapi.get(&hclist.metadata.name.clone().expect("HC lookup issue")).await.unwrap();
-                let srv_namespace = hc.spec.serv_namespace;
-                let timeout = hc.spec.timeout;
-                let port = hc.spec.port;
-                let seen_before = actions::check_if_seen_before(client.clone(), &name).await;
-                let hcpod_ip = actions::get_hc_pod_ip(client.clone(), &name, &srv_namespace, port.clone()).await;
-                let mut result = false;
-                let null_ip = "0.0.0.0".to_string();
-
-                if !hcpod_ip.contains(&null_ip) {
-                    for ip in hcpod_ip {
-                        result = actions::check_port(ip.clone(), port, timeout).await;
-                        println!("Port check passed status: {:?}", result);
-
-                        let state = actions::get_state(port.clone(), ip.clone(), &cluster_name).await;
-                        //let state = actions::get_state(port.clone(), ip.clone(), &cluster_name).await;
-
-                        println!("{:?}: Lastmode Empty {:?} - Current State Empty {:?} - TCP HC Result {:?}", ip.clone(), state.0.is_empty(), state.1.is_empty(), result);
-                        if result == true && state.1 == "accept" {
-                            return Ok(Action::requeue(Duration::from_secs(10)))
-                        } else if result == false && state.1 == "drain" {
-                            return Ok(Action::requeue(Duration::from_secs(10)))
-                        } else if state.1 == "accept" && result == false {
-                            let _ = actions::remove_from_nb(client.clone(), &name, port.clone(), ip.clone(), &cluster_name).await;
-                            println!("Node {:?} removed from NodeBalancer - unreachable", &name);
-                        } else if state.1 == "drain" && result == true {
-                            let _ = actions::add_to_nb(client.clone(), &name, port.clone(), ip.clone(), &cluster_name).await;
-                            println!("Node {:?} init into state DB", &name);
-                        } else if state.0.is_empty() && state.1.is_empty() && result == false {
-                            let _ = actions::remove_from_nb(client.clone(), &name, port.clone(), ip.clone(), &cluster_name).await;
-                            println!("Node {:?} removed from NodeBalancer - unreachable", &name);
-                        } else if state.0.is_empty() && state.1.is_empty() && result == true {
-                            let _ = actions::add_to_nb(client.clone(), &name, port.clone(), ip.clone(), &cluster_name).await;
-                            println!("Node {:?} init into state DB", &name);
-                        }
+                let hc = hcapi.get(&hclist.name_any()).await?;
+                let hc_ref = hc.object_ref(&());
+                // A broken HealthCheck is reported on itself and doesn't hold up the others.
+                match reconcile_healthcheck(hc, &node, &context, node_override, drain_trigger).await {
+                    Err(Error::UserInputError(e)) => {
+                        eprintln!("Skipping HealthCheck {} on node {:?}: {}", hclist.name_any(), &name, e);
+                        actions::publish_healthcheck_event(&context.recorder, &hc_ref, e).await;
                     }
-                } else {
-                    return Ok(Action::requeue(Duration::from_secs(10)))
+                    result => result?,
                 }
             }
-            return Ok(Action::requeue(Duration::from_secs(10)))
+            Ok(Action::requeue(Duration::from_secs(10)))
         }
         HealthCheckAction::Delete => {
             Ok(Action::await_change())
@@ -150,6 +287,236 @@ async fn reconcile(node: Arc<Node>, context: Arc<ContextData>) -> Result<Action,
     }
 }
 
+async fn reconcile_healthcheck(
+    hc: HealthCheck,
+    node: &Node,
+    context: &ContextData,
+    node_override: Option<actions::NodeOverride>,
+    drain_trigger: Option<&'static str>,
+) -> Result<(), Error> {
+    let client: Client = context.client.clone();
+    let name = node.name_any();
+    let cluster_name = context.cluster_name.clone();
+    let hc_name = hc.name_any();
+    let hc_uid = hc.uid().ok_or(Error::UserInputError(format!("HealthCheck {} has no UID", hc_name)))?;
+    hc.spec.validate().map_err(|e| Error::UserInputError(format!("{}: {}", hc_name, e)))?;
+    let mappings = hc.spec.port_mappings();
+    let srv_namespace = hc.spec.serv_namespace;
+    let timeout = hc.spec.timeout;
+    let taint_unhealthy = hc.spec.taint_unhealthy;
+    let drain_grace_period = hc.spec.drain_grace_period;
+    let drain_escalation = hc.spec.drain_escalation;
+    let weighting = hc.spec.weighting;
+    let dry_run = *DRY_RUN || hc.spec.dry_run;
+    let in_maintenance = maintenance::in_maintenance(&hc.spec.maintenance_windows, chrono::Utc::now())
+        .map_err(|e| Error::UserInputError(format!("{}: {}", hc_name, e)))?;
+    let scope = actions::resolve_scope(client.clone(), &hc.spec.scope, &srv_namespace).await?;
+    // The node's verdict over every mapping, once the NodeBalancer has it.
+    let mut node_healthy = None;
+
+    for mapping in &mappings {
+        let port = mapping.nodebalancer_port;
+        // Without pods to probe, no_pods decides the verdict for the node's own addresses.
+        let mut no_pods_verdict = None;
+        let node_targets = || actions::get_private_addresses(node).into_iter().map(|ip| actions::ProbeAddress::node(ip, None)).collect::<Vec<_>>();
+        let targets = match hc.spec.target {
+            ProbeTarget::NodeIP => actions::node_probe_addresses(node, port, &scope).await?,
+            ProbeTarget::PodIP => {
+                let pod_ips = match &hc.spec.service {
+                    Some(service) => actions::get_service_targets(client.clone(), &name, &srv_namespace, service, port).await?,
+                    None => actions::get_hc_pod_ip(client.clone(), &name, &srv_namespace).await?,
+                };
+                if pod_ips.is_empty() {
+                    match hc.spec.no_pods {
+                        NoPodsPolicy::Ignore => {
+                            println!("No pods for {} on node {:?} - skipping", hc_name, &name);
+                            continue;
+                        }
+                        NoPodsPolicy::Healthy => no_pods_verdict = Some(true),
+                        NoPodsPolicy::Unhealthy => no_pods_verdict = Some(false),
+                    }
+                    node_targets()
+                } else {
+                    pod_ips
+                }
+            }
+        };
+        if targets.is_empty() {
+            return Err(Error::MissingNodeAddress(name));
+        }
+
+        let mut verdicts = Vec::new();
+        for target in targets {
+            let ip = target.ip;
+            let verdict = if let Some(node_override) = node_override {
+                println!("Node {:?} override {:?} takes priority over probe result", &name, node_override);
+                actions::Verdict::new(node_override == actions::NodeOverride::Accept, "Override")
+            } else if let Some(trigger) = drain_trigger {
+                println!("Node {:?} draining ahead of probes: {}", &name, trigger);
+                actions::Verdict::new(false, trigger)
+            } else if target.terminating {
+                println!("Pod {} on node {:?} is terminating - draining ahead of shutdown", ip, &name);
+                actions::Verdict::new(false, "PodTerminating")
+            } else if let Some(healthy) = no_pods_verdict {
+                actions::Verdict::new(healthy, "NoPods")
+            } else if hc.spec.source == ProbeSource::PodReadiness {
+                let result = target.not_ready.is_none();
+                println!("Pod {} readiness: {:?}", ip, target.not_ready.as_deref().unwrap_or("ready"));
+                actions::Verdict {
+                    probe_type: "podReadiness",
+                    probe_error: target.not_ready.clone(),
+                    ..actions::Verdict::new(result, if result { "PodReady" } else { "PodNotReady" })
+                }
+            } else if let Some(probes) = hc.spec.probes.as_ref().filter(|probes| !probes.is_empty()) {
+                let mut results = BTreeMap::new();
+                let mut failed = Vec::new();
+                let mut slowest: f64 = 0.0;
+                for probe in probes {
+                    let probe_port = actions::resolve_port(&probe.port, &target)?;
+                    let started = Instant::now();
+                    let passed = actions::check_port(ip, probe_port, timeout).await?;
+                    slowest = slowest.max(started.elapsed().as_secs_f64() * 1000.0);
+                    if !passed {
+                        failed.push(format!("{} (port {})", probe.name, probe_port));
+                    }
+                    results.insert(probe.name.clone(), passed);
+                }
+                let result = hc.spec.expression.evaluate(&results).map_err(|e| Error::UserInputError(format!("{}: {}", hc_name, e)))?;
+                println!("Probes {:?} on {} - verdict {:?}", results, ip, result);
+                actions::Verdict {
+                    probe_type: "composite",
+                    probe_error: (!failed.is_empty()).then(|| format!("tcp connect to {} failed within {}s: {}", ip, timeout, failed.join(", "))),
+                    latency_ms: Some(slowest),
+                    ..actions::Verdict::new(result, if result { "ProbePassed" } else { "ProbeFailed" })
+                }
+            } else {
+                let started = Instant::now();
+                let probe_port = actions::probe_port(mapping, &target)?;
+                let result = actions::check_port(ip, probe_port, timeout).await?;
+                println!("Port check passed status: {:?}", result);
+                actions::Verdict {
+                    probe_type: "tcp",
+                    probe_error: (!result).then(|| format!("tcp connect to {} port {} failed within {}s", ip, probe_port, timeout)),
+                    latency_ms: Some(started.elapsed().as_secs_f64() * 1000.0),
+                    ..actions::Verdict::new(result, if result { "ProbePassed" } else { "ProbeFailed" })
+                }
+            };
+            verdicts.push((target, verdict));
+        }
+
+        // Targets of one address family are behind the same NodeBalancer
+        // nodes (see get_nb_nodes), so they get a single verdict.
+        for ipv4 in [true, false] {
+            let (group, group_verdicts): (Vec<&actions::ProbeAddress>, Vec<actions::Verdict>) = verdicts
+                .iter()
+                .filter(|(target, _)| target.ip.is_ipv4() == ipv4)
+                .map(|(target, verdict)| (target, verdict.clone()))
+                .unzip();
+            let Some((index, verdict)) = actions::combine_verdicts(&group_verdicts) else { continue };
+            let ip = group[index].ip;
+            let pod_uid = group[index].pod_uid.clone();
+            let result = verdict.healthy;
+            let reason = verdict.reason;
+            let latency_ms = verdict.latency_ms;
+            let state = actions::get_state(&hc_uid, &name, port, ip, dry_run).await?;
+            let decision = actions::Decision {
+                healthcheck: &hc_name,
+                healthcheck_uid: &hc_uid,
+                pod_uid: &pod_uid,
+                scope: &scope,
+                reason,
+                probe_type: verdict.probe_type,
+                probe_error: verdict.probe_error,
+                latency_ms,
+                dry_run,
+            };
+
+            println!("{}: Lastmode Empty {:?} - Current State Empty {:?} - TCP HC Result {:?}", ip, state.lastmode.is_empty(), state.current.is_empty(), result);
+            let drained = matches!(state.current.as_str(), "drain" | "reject" | "backup");
+            if (result && state.current == "accept") || (!result && drained) {
+                if let (Some(grace), Some(started)) = (drain_grace_period, state.drain_started) {
+                    if state.current == "drain" && !in_maintenance && chrono::Utc::now().timestamp() - started >= grace as i64 {
+                        let mode = drain_escalation.mode();
+                        let escalation = actions::Decision { reason: "DrainGracePeriodExpired", probe_error: decision.probe_error.clone(), ..decision };
+                        actions::set_nb_mode(client.clone(), &name, port, ip, &cluster_name, mode, &escalation).await?;
+                        println!("Node {:?} drained for over {}s - set to {}", &name, grace, mode);
+                        record_decision(&hc_name, mode, "DrainGracePeriodExpired", dry_run);
+                        if !dry_run {
+                            actions::update_hc_status(client.clone(), &hc_name, &name, mode, "DrainGracePeriodExpired").await;
+                        }
+                        let note = format!("HealthCheck {} set pod {} port {} to {} after {}s in drain", hc_name, ip, port, mode, grace);
+                        actions::publish_node_event(&context.recorder, node, mode, "DrainGracePeriodExpired", note, dry_run).await;
+                    }
+                }
+                if let (Some(weighting), Some(latency_ms)) = (&weighting, latency_ms) {
+                    if result && !in_maintenance && !dry_run {
+                        let (smoothed, weight) = actions::next_weight(&state, latency_ms, weighting);
+                        if state.weight != Some(weight) {
+                            actions::set_nb_weight(client.clone(), &name, port, ip, &cluster_name, &scope, weight).await?;
+                        }
+                        for node_id in &state.node_ids {
+                            store().update_weight_state(&hc_uid, &name, *node_id, smoothed, weight).await?;
+                        }
+                    }
+                }
+                node_healthy = Some(node_healthy.unwrap_or(true) && result);
+                continue;
+            }
+            let needs_change = state.current == "accept" || state.current == "mixed" || drained || (state.lastmode.is_empty() && state.current.is_empty());
+            if !needs_change {
+                continue;
+            }
+            if in_maintenance {
+                println!("Maintenance window active for {} - deferring {} of node {:?}", hc_name, if result { "accept" } else { "drain" }, &name);
+                continue;
+            }
+            let mode = if result {
+                actions::add_to_nb(client.clone(), &name, port, ip, &cluster_name, &decision).await?;
+                println!("Node {:?} added to NodeBalancer", &name);
+                "accept"
+            } else {
+                actions::remove_from_nb(client.clone(), &name, port, ip, &cluster_name, &decision).await?;
+                println!("Node {:?} removed from NodeBalancer - {}", &name, reason);
+                "drain"
+            };
+            record_decision(&hc_name, mode, reason, dry_run);
+            if !dry_run {
+                actions::update_hc_status(client.clone(), &hc_name, &name, mode, reason).await;
+            }
+            node_healthy = Some(node_healthy.unwrap_or(true) && result);
+            let note = format!("HealthCheck {} set pod {} port {} to {}", hc_name, ip, port, mode);
+            actions::publish_node_event(&context.recorder, node, mode, reason, note, dry_run).await;
+        }
+        if !dry_run {
+            for (target, _) in &verdicts {
+                actions::sync_readiness_gate(client.clone(), &srv_namespace, &name, port, target, &scope, &cluster_name).await;
+            }
+        }
+    }
+    if let (Some(healthy), false) = (node_healthy, dry_run) {
+        actions::set_node_verdict(client.clone(), &name, &hc_name, healthy, taint_unhealthy).await;
+    }
+    Ok(())
+}
+
+fn record_decision(hc_name: &str, mode: &str, reason: &str, dry_run: bool) {
+    metrics::inc(
+        "hc_operator_decisions_total",
+        &[("healthcheck", hc_name), ("mode", mode), ("reason", reason), ("dry_run", if dry_run { "true" } else { "false" })],
+    );
+}
+
+// Pod changes in a namespace a HealthCheck targets reconcile the pod's node
+// straight away, instead of waiting for the node to requeue.
+fn pod_node(healthchecks: &reflector::Store<HealthCheck>, pod: &Pod) -> Option<ObjectRef<Node>> {
+    let namespace = pod.namespace()?;
+    if !healthchecks.state().iter().any(|hc| hc.spec.serv_namespace == namespace) {
+        return None;
+    }
+    let node_name = pod.spec.as_ref()?.node_name.as_deref()?;
+    Some(ObjectRef::new(node_name))
+}
+
 fn determine_action(node: &Node) -> HealthCheckAction {
     if node.meta().deletion_timestamp.is_some() {
         HealthCheckAction::Delete
@@ -165,9 +532,18 @@ fn determine_action(node: &Node) -> HealthCheckAction {
     }
 }
 
+// Transient API and database failures are retried soon, problems that need
+// someone to fix the node or the HealthCheck much less often.
 fn on_error(node: Arc<Node>, error: &Error, _context: Arc<ContextData>) -> Action {
-    eprintln!("Reconciliation error:\n{:?}.\n{:?}", error, node);
-    Action::requeue(Duration::from_secs(5))
+    eprintln!("Reconciliation error for node {:?}: {}", node.metadata.name, error);
+    let delay = match error {
+        Error::KubeError { .. } => 5,
+        Error::ProbeError(_) => 10,
+        Error::StoreError { .. } | Error::NodeBalancerError(_) => 30,
+        Error::MissingNodeAddress(_) => 60,
+        Error::MissingClusterIdentity(_) | Error::UserInputError(_) | Error::OwnershipError(_) => 300,
+    };
+    Action::requeue(Duration::from_secs(delay))
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -179,4 +555,19 @@ pub enum Error {
     },
     #[error("Invalid HealthCheck CRD: {0}")]
     UserInputError(String),
+    #[error("State store error: {source}")]
+    StoreError {
+        #[from]
+        source: store::StoreError,
+    },
+    #[error("NodeBalancer API error: {0}")]
+    NodeBalancerError(String),
+    #[error("Cannot determine cluster identity: {0}")]
+    MissingClusterIdentity(String),
+    #[error("Node {0} has no InternalIP address")]
+    MissingNodeAddress(String),
+    #[error("Probe failed: {0}")]
+    ProbeError(String),
+    #[error("NodeBalancer not owned by this cluster: {0}")]
+    OwnershipError(String),
 }
@@ -0,0 +1,227 @@
+use crate::crd::MaintenanceWindow;
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+
+// Longest cron window we are willing to scan back over, one week.
+const MAX_WINDOW_SECS: u64 = 7 * 24 * 60 * 60;
+
+pub fn in_maintenance(windows: &Option<Vec<MaintenanceWindow>>, now: DateTime<Utc>) -> Result<bool, String> {
+    let Some(windows) = windows else { return Ok(false) };
+    for window in windows {
+        if window_active(window, now)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn window_active(window: &MaintenanceWindow, now: DateTime<Utc>) -> Result<bool, String> {
+    if let Some(schedule) = &window.schedule {
+        let duration = window.duration.ok_or(format!("maintenance window {:?} has no duration", schedule))?;
+        if duration > MAX_WINDOW_SECS {
+            return Err(format!("maintenance window {:?} duration {}s exceeds {}s", schedule, duration, MAX_WINDOW_SECS));
+        }
+        let cron = Cron::parse(schedule)?;
+        // Walk back minute by minute over the window length looking for a start time.
+        let mut minute = now.with_second(0).and_then(|t| t.with_nanosecond(0)).unwrap_or(now);
+        let earliest = now - Duration::seconds(duration as i64);
+        while minute > earliest {
+            if cron.matches(minute) {
+                return Ok(true);
+            }
+            minute -= Duration::minutes(1);
+        }
+        return Ok(false);
+    }
+
+    let (Some(start), Some(end)) = (&window.start, &window.end) else {
+        return Err("maintenance window needs either schedule and duration or start and end".to_string());
+    };
+    let start = DateTime::parse_from_rfc3339(start).map_err(|e| format!("invalid start {:?}: {}", start, e))?;
+    let end = DateTime::parse_from_rfc3339(end).map_err(|e| format!("invalid end {:?}: {}", end, e))?;
+    Ok(start <= now && now < end)
+}
+
+struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    days_any: bool,
+    weekdays_any: bool,
+}
+
+impl Cron {
+    fn parse(schedule: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = schedule.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("schedule {:?} must have 5 fields", schedule));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 mean Sunday.
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        Ok(Cron {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            days_any: fields[2] == "*",
+            weekdays_any: fields[4] == "*",
+        })
+    }
+
+    fn matches(&self, t: DateTime<Utc>) -> bool {
+        let day = self.days[t.day() as usize];
+        let weekday = self.weekdays[t.weekday().num_days_from_sunday() as usize];
+        // Standard cron: when both day fields are restricted either may match.
+        let day_matches = match (self.days_any, self.weekdays_any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        self.minutes[t.minute() as usize] && self.hours[t.hour() as usize] && self.months[t.month() as usize] && day_matches
+    }
+}
+
+fn parse_field(field: &str, min: usize, max: usize) -> Result<Vec<bool>, String> {
+    let mut allowed = vec![false; max + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>().map_err(|_| format!("invalid step in {:?}", field))?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("invalid step in {:?}", field));
+        }
+        let (low, high) = if range == "*" {
+            (min, max)
+        } else if let Some((low, high)) = range.split_once('-') {
+            (parse_value(low, field)?, parse_value(high, field)?)
+        } else {
+            let value = parse_value(range, field)?;
+            (value, if part.contains('/') { max } else { value })
+        };
+        if low < min || high > max || low > high {
+            return Err(format!("value out of range in {:?}", field));
+        }
+        for value in (low..=high).step_by(step) {
+            allowed[value] = true;
+        }
+    }
+    Ok(allowed)
+}
+
+fn parse_value(value: &str, field: &str) -> Result<usize, String> {
+    value.parse::<usize>().map_err(|_| format!("invalid value {:?} in {:?}", value, field))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(timestamp: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(timestamp).unwrap().with_timezone(&Utc)
+    }
+
+    fn values(allowed: &[bool]) -> Vec<usize> {
+        allowed.iter().enumerate().filter(|(_, allowed)| **allowed).map(|(value, _)| value).collect()
+    }
+
+    fn scheduled(schedule: &str, duration: u64) -> MaintenanceWindow {
+        MaintenanceWindow { schedule: Some(schedule.to_string()), duration: Some(duration), start: None, end: None }
+    }
+
+    #[test]
+    fn parse_field_values_ranges_and_lists() {
+        assert_eq!(values(&parse_field("5", 0, 59).unwrap()), vec![5]);
+        assert_eq!(values(&parse_field("1-3,7", 0, 59).unwrap()), vec![1, 2, 3, 7]);
+        assert_eq!(values(&parse_field("*", 1, 12).unwrap()), (1..=12).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn parse_field_steps() {
+        assert_eq!(values(&parse_field("*/15", 0, 59).unwrap()), vec![0, 15, 30, 45]);
+        assert_eq!(values(&parse_field("10-20/5", 0, 59).unwrap()), vec![10, 15, 20]);
+        // A single value with a step runs to the end of the field.
+        assert_eq!(values(&parse_field("5/20", 0, 59).unwrap()), vec![5, 25, 45]);
+    }
+
+    #[test]
+    fn parse_field_rejects_invalid() {
+        for field in ["60", "5-1", "*/0", "a", "1-", ""] {
+            assert!(parse_field(field, 0, 59).is_err(), "{:?} should not parse", field);
+        }
+        assert!(parse_field("0", 1, 31).is_err());
+    }
+
+    #[test]
+    fn cron_needs_five_fields() {
+        assert!(Cron::parse("0 2 * *").is_err());
+        assert!(Cron::parse("0 2 * * * *").is_err());
+    }
+
+    #[test]
+    fn cron_sunday_is_zero_or_seven() {
+        let cron = Cron::parse("0 0 * * 7").unwrap();
+        assert!(cron.matches(at("2026-06-07T00:00:00Z")));
+        assert!(!cron.matches(at("2026-06-08T00:00:00Z")));
+    }
+
+    #[test]
+    fn cron_day_of_month_or_day_of_week() {
+        // Both restricted: either matches. 2026-07-01 is a Wednesday, 2026-07-06 a Monday.
+        let both = Cron::parse("0 0 1 * 1").unwrap();
+        assert!(both.matches(at("2026-07-01T00:00:00Z")));
+        assert!(both.matches(at("2026-07-06T00:00:00Z")));
+        assert!(!both.matches(at("2026-07-07T00:00:00Z")));
+        // Only one restricted: that one has to match.
+        let day = Cron::parse("0 0 1 * *").unwrap();
+        assert!(day.matches(at("2026-07-01T00:00:00Z")));
+        assert!(!day.matches(at("2026-07-06T00:00:00Z")));
+        let weekday = Cron::parse("0 0 * * 1").unwrap();
+        assert!(!weekday.matches(at("2026-07-01T00:00:00Z")));
+        assert!(weekday.matches(at("2026-07-06T00:00:00Z")));
+    }
+
+    #[test]
+    fn schedule_window_boundaries() {
+        let windows = Some(vec![scheduled("0 2 * * *", 3600)]);
+        assert!(!in_maintenance(&windows, at("2026-07-01T01:59:59Z")).unwrap());
+        assert!(in_maintenance(&windows, at("2026-07-01T02:00:00Z")).unwrap());
+        assert!(in_maintenance(&windows, at("2026-07-01T02:59:59Z")).unwrap());
+        assert!(!in_maintenance(&windows, at("2026-07-01T03:00:00Z")).unwrap());
+    }
+
+    #[test]
+    fn schedule_window_spans_midnight() {
+        let windows = Some(vec![scheduled("30 23 * * 3", 3600)]);
+        assert!(in_maintenance(&windows, at("2026-07-02T00:15:00Z")).unwrap());
+        assert!(!in_maintenance(&windows, at("2026-07-02T00:30:00Z")).unwrap());
+    }
+
+    #[test]
+    fn absolute_window_boundaries() {
+        let windows = Some(vec![MaintenanceWindow {
+            schedule: None,
+            duration: None,
+            start: Some("2026-07-01T02:00:00Z".to_string()),
+            end: Some("2026-07-01T03:00:00Z".to_string()),
+        }]);
+        assert!(!in_maintenance(&windows, at("2026-07-01T01:59:59Z")).unwrap());
+        assert!(in_maintenance(&windows, at("2026-07-01T02:00:00Z")).unwrap());
+        assert!(!in_maintenance(&windows, at("2026-07-01T03:00:00Z")).unwrap());
+    }
+
+    #[test]
+    fn invalid_windows() {
+        let now = at("2026-07-01T02:00:00Z");
+        let no_duration = MaintenanceWindow { duration: None, ..scheduled("0 2 * * *", 0) };
+        assert!(in_maintenance(&Some(vec![no_duration]), now).is_err());
+        assert!(in_maintenance(&Some(vec![scheduled("0 2 * * *", MAX_WINDOW_SECS + 1)]), now).is_err());
+        let empty = MaintenanceWindow { schedule: None, duration: None, start: None, end: None };
+        assert!(in_maintenance(&Some(vec![empty]), now).is_err());
+        assert!(!in_maintenance(&None, now).unwrap());
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+use std::sync::{LazyLock, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+// Counters keyed by their full Prometheus series, e.g. `name{label="value"}`.
+static COUNTERS: LazyLock<Mutex<BTreeMap<String, u64>>> = LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+pub fn inc(name: &str, labels: &[(&str, &str)]) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+    let series = format!("{}{{{}}}", name, labels.join(","));
+    *COUNTERS.lock().unwrap().entry(series).or_insert(0) += 1;
+}
+
+fn render() -> String {
+    let counters = COUNTERS.lock().unwrap();
+    let mut out = String::new();
+    let mut last_name = "";
+    for (series, value) in counters.iter() {
+        let name = series.split('{').next().unwrap_or(series);
+        if name != last_name {
+            out.push_str(&format!("# TYPE {} counter\n", name));
+            last_name = name;
+        }
+        out.push_str(&format!("{} {}\n", series, value));
+    }
+    out
+}
+
+// Minimal HTTP endpoint so Prometheus can scrape the counters.
+pub async fn serve(addr: String) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Metrics listener on {} failed: {:?}", addr, e);
+            return;
+        }
+    };
+    println!("Serving metrics on {}", addr);
+    loop {
+        let Ok((mut socket, _)) = listener.accept().await else { continue };
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            let body = render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+        });
+    }
+}
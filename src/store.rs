@@ -0,0 +1,391 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use kube::Client;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use crate::database::{LocalNodeBalancerListObject, NodeBalancerConfigObject, NodeObject};
+
+mod kubernetes;
+mod memory;
+mod postgres;
+mod sqlite;
+
+pub use kubernetes::KubernetesStore;
+pub use memory::MemoryStore;
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+static STORE: OnceLock<Box<dyn StateStore>> = OnceLock::new();
+
+// Backend picked by STATE_STORE: "postgres" (default), "sqlite",
+// "kubernetes" or "memory". Called once at startup.
+pub fn init(client: Client) {
+    let backend: Box<dyn StateStore> = match env::var("STATE_STORE").as_deref() {
+        Ok("sqlite") => {
+            let path = env::var("SQLITE_PATH").unwrap_or("hc-operator.db".to_string());
+            Box::new(SqliteStore::open(&path).expect("unable to open SQLite state store"))
+        }
+        Ok("kubernetes") => Box::new(KubernetesStore::new(client)),
+        Ok("memory") => Box::new(MemoryStore::default()),
+        _ => Box::new(PostgresStore),
+    };
+    let _ = STORE.set(backend);
+}
+
+pub fn store() -> &'static dyn StateStore {
+    STORE.get().expect("state store not initialised").as_ref()
+}
+
+// Settings the chosen backend needs, checked once at startup.
+pub fn check_config() -> Result<(), String> {
+    match env::var("STATE_STORE").as_deref() {
+        Ok("sqlite") | Ok("kubernetes") | Ok("memory") => Ok(()),
+        _ => crate::database::check_config(),
+    }
+}
+
+// Stores other than Postgres have no external job filling in the
+// NodeBalancer inventory, so the operator syncs it from the API itself.
+pub fn needs_inventory_sync() -> bool {
+    matches!(env::var("STATE_STORE").as_deref(), Ok("sqlite") | Ok("kubernetes") | Ok("memory"))
+}
+
+// Apply an update to an existing row, or start a new one, for stores that
+// don't do it in SQL.
+pub fn apply_update(existing: Option<StateRow>, update: &StateUpdate) -> StateRow {
+    let mut row = existing.unwrap_or_else(|| StateRow {
+        healthcheck: update.healthcheck.clone(),
+        node_name: update.node_name.clone(),
+        nodebalancer_id: update.nodebalancer_id,
+        nodebalancer_config_id: update.nodebalancer_config_id,
+        node_id: update.node_id,
+        podip: update.podip.clone(),
+        pod_uid: update.pod_uid.clone(),
+        port: update.port,
+        cluster_name: update.cluster_name.clone(),
+        ..Default::default()
+    });
+    row.drain_started = match (update.current.as_str(), row.drain_started) {
+        ("drain", Some(started)) if row.current == "drain" => Some(started),
+        ("drain", _) => Some(chrono::Utc::now().timestamp()),
+        _ => None,
+    };
+    row.nodebalancer_config_id = update.nodebalancer_config_id;
+    row.podip = update.podip.clone();
+    row.pod_uid = update.pod_uid.clone();
+    row.port = update.port;
+    row.lastmode = update.lastmode.clone();
+    row.current = update.current.clone();
+    row
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("Postgres error: {0}")]
+    Postgres(#[from] crate::database::Error),
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Kubernetes reported error: {0}")]
+    Kube(#[from] kube::Error),
+    #[error("Invalid stored state: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("State store error: {0}")]
+    Other(String),
+}
+
+// One row of the state table: a HealthCheck's verdict for one NodeBalancer
+// node, keyed by (healthcheck, node_name, node_id). healthcheck is the UID.
+// pod_uid is the pod podip belonged to, empty when a node address was probed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct StateRow {
+    pub healthcheck: String,
+    pub node_name: String,
+    pub nodebalancer_id: i32,
+    pub nodebalancer_config_id: i32,
+    pub node_id: i32,
+    pub podip: String,
+    pub pod_uid: String,
+    pub port: i32,
+    pub lastmode: String,
+    pub current: String,
+    pub cluster_name: String,
+    pub drain_started: Option<i64>,
+    pub latency_ms: Option<f64>,
+    pub weight: Option<i32>,
+}
+
+// A mode written to the state table. The store sets drain_started itself
+// when current moves into drain and keeps it while the node stays there.
+#[derive(Debug, Clone)]
+pub struct StateUpdate {
+    pub healthcheck: String,
+    pub node_name: String,
+    pub nodebalancer_id: i32,
+    pub nodebalancer_config_id: i32,
+    pub node_id: i32,
+    pub podip: String,
+    pub pod_uid: String,
+    pub port: i32,
+    pub lastmode: String,
+    pub current: String,
+    pub cluster_name: String,
+}
+
+// NodeBalancer node addresses are `ip:port`, with IPv6 addresses in brackets.
+pub fn address_prefix(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => format!("{}:", ip),
+        IpAddr::V6(ip) => format!("[{}]:", ip),
+    }
+}
+
+// A NodeBalancer backend node, as returned by get_by_node_ip_nbcfg.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NbNode {
+    pub node_id: i32,
+    pub config_id: i32,
+    pub nodebalancer_id: i32,
+    // ip:port or [ip]:port the NodeBalancer sends traffic to.
+    pub address: String,
+}
+
+// NodeBalancers and configs a HealthCheck may change. None leaves that
+// level unrestricted, an empty list matches nothing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NbScope {
+    pub nodebalancer_ids: Option<Vec<i32>>,
+    pub config_ids: Option<Vec<i32>>,
+}
+
+impl NbScope {
+    pub fn matches(&self, node: &NbNode) -> bool {
+        self.nodebalancer_ids.as_ref().is_none_or(|ids| ids.contains(&node.nodebalancer_id))
+            && self.config_ids.as_ref().is_none_or(|ids| ids.contains(&node.config_id))
+    }
+}
+
+// One entry of the append-only mode_change_audit log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub changed_at: DateTime<Utc>,
+    pub cluster_name: String,
+    pub node_name: String,
+    pub podip: String,
+    pub nodebalancer_id: i32,
+    pub nodebalancer_config_id: i32,
+    pub node_id: i32,
+    pub old_mode: String,
+    pub new_mode: String,
+    pub healthcheck: String,
+    pub reason: String,
+    pub probe_type: String,
+    pub error: Option<String>,
+    pub latency_ms: Option<f64>,
+    pub api_success: bool,
+}
+
+// Filters for reading the audit log back, newest first.
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub node_name: Option<String>,
+    pub healthcheck: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub limit: i64,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        self.node_name.as_ref().is_none_or(|n| *n == record.node_name)
+            && self.healthcheck.as_ref().is_none_or(|h| *h == record.healthcheck)
+            && self.since.is_none_or(|since| record.changed_at >= since)
+    }
+}
+
+// The shadow table holds decisions made in dry run.
+#[async_trait]
+pub trait StateStore: Send + Sync {
+    async fn migrate(&self) -> Result<(), StoreError>;
+    // Rows of one HealthCheck on one node, one per NodeBalancer node.
+    async fn get_state(&self, healthcheck: &str, node_name: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError>;
+    // Rows of every HealthCheck for one NodeBalancer node.
+    async fn get_nb_node_states(&self, node_id: i32, clustername: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError>;
+    async fn update_state(&self, update: &StateUpdate, shadow: bool) -> Result<(), StoreError>;
+    async fn get_cluster_states(&self, clustername: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError>;
+    async fn delete_state(&self, healthcheck: &str, node_name: &str, node_id: i32, shadow: bool) -> Result<(), StoreError>;
+    async fn update_weight_state(&self, healthcheck: &str, node_name: &str, node_id: i32, latency_ms: f64, weight: i32) -> Result<(), StoreError>;
+    async fn get_by_node_ip_nbcfg(&self, ip: IpAddr, port: i32, scope: &NbScope) -> Result<Vec<NbNode>, StoreError>;
+    async fn get_nodebalancer_by_ip(&self, ip: &str) -> Result<Option<i32>, StoreError>;
+    async fn update_db_nb(&self, nodebalancer: LocalNodeBalancerListObject) -> Result<(), StoreError>;
+    async fn update_db_config(&self, nodebalancer_config: NodeBalancerConfigObject) -> Result<(), StoreError>;
+    async fn update_db_node(&self, node: NodeObject) -> Result<(), StoreError>;
+    // Delete the inventory rows whose ID isn't in the given lists.
+    async fn prune_inventory(&self, nodebalancer_ids: &[i32], config_ids: &[i32], node_ids: &[i32]) -> Result<(), StoreError>;
+    async fn record_audit(&self, record: &AuditRecord) -> Result<(), StoreError>;
+    async fn get_audit(&self, query: &AuditQuery) -> Result<Vec<AuditRecord>, StoreError>;
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use serde_json::json;
+
+    pub fn update(healthcheck: &str, node_id: i32, current: &str) -> StateUpdate {
+        StateUpdate {
+            healthcheck: healthcheck.to_string(),
+            node_name: "node-a".to_string(),
+            nodebalancer_id: 1,
+            nodebalancer_config_id: 10,
+            node_id,
+            podip: "10.2.0.5".to_string(),
+            pod_uid: "pod-1".to_string(),
+            port: 80,
+            lastmode: current.to_string(),
+            current: current.to_string(),
+            cluster_name: "cluster".to_string(),
+        }
+    }
+
+    #[test]
+    fn apply_update_starts_a_row() {
+        let row = apply_update(None, &update("hc", 100, "accept"));
+        assert_eq!(row.healthcheck, "hc");
+        assert_eq!(row.node_name, "node-a");
+        assert_eq!((row.nodebalancer_id, row.nodebalancer_config_id, row.node_id, row.port), (1, 10, 100, 80));
+        assert_eq!(row.current, "accept");
+        assert_eq!(row.drain_started, None);
+    }
+
+    #[test]
+    fn apply_update_tracks_drain_start() {
+        let row = apply_update(None, &update("hc", 100, "drain"));
+        assert!(row.drain_started.is_some());
+        let started = StateRow { drain_started: Some(1), ..row };
+        let row = apply_update(Some(started), &update("hc", 100, "drain"));
+        assert_eq!(row.drain_started, Some(1));
+        let row = apply_update(Some(row), &update("hc", 100, "reject"));
+        assert_eq!(row.drain_started, None);
+        assert_eq!(row.current, "reject");
+    }
+
+    #[test]
+    fn apply_update_keeps_weight() {
+        let existing = StateRow { latency_ms: Some(12.0), weight: Some(40), ..apply_update(None, &update("hc", 100, "accept")) };
+        let moved = StateUpdate { podip: "10.2.0.6".to_string(), pod_uid: "pod-2".to_string(), ..update("hc", 100, "accept") };
+        let row = apply_update(Some(existing), &moved);
+        assert_eq!((row.podip.as_str(), row.pod_uid.as_str()), ("10.2.0.6", "pod-2"));
+        assert_eq!((row.latency_ms, row.weight), (Some(12.0), Some(40)));
+    }
+
+    // The same checks for every backend that runs without outside services.
+    pub async fn state_round_trip(store: &dyn StateStore) {
+        store.migrate().await.unwrap();
+        store.update_state(&update("hc-1", 100, "accept"), false).await.unwrap();
+        store.update_state(&update("hc-1", 101, "accept"), false).await.unwrap();
+        store.update_state(&update("hc-2", 100, "drain"), false).await.unwrap();
+        store.update_state(&update("hc-1", 100, "drain"), true).await.unwrap();
+
+        let rows = store.get_state("hc-1", "node-a", false).await.unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|row| row.current == "accept"));
+        let shadow = store.get_state("hc-1", "node-a", true).await.unwrap();
+        assert_eq!(shadow.len(), 1);
+        assert_eq!(shadow[0].current, "drain");
+        assert!(store.get_state("hc-1", "node-b", false).await.unwrap().is_empty());
+
+        let mut modes: Vec<String> = store.get_nb_node_states(100, "cluster", false).await.unwrap().into_iter().map(|row| row.current).collect();
+        modes.sort();
+        assert_eq!(modes, vec!["accept", "drain"]);
+        assert!(store.get_nb_node_states(100, "other", false).await.unwrap().is_empty());
+
+        store.update_state(&update("hc-1", 100, "drain"), false).await.unwrap();
+        let rows = store.get_state("hc-1", "node-a", false).await.unwrap();
+        let row = rows.iter().find(|row| row.node_id == 100).unwrap();
+        assert_eq!(row.current, "drain");
+        assert!(row.drain_started.is_some());
+
+        store.update_weight_state("hc-1", "node-a", 100, 25.0, 60).await.unwrap();
+        let rows = store.get_state("hc-1", "node-a", false).await.unwrap();
+        let weights: Vec<(i32, Option<i32>)> = rows.iter().map(|row| (row.node_id, row.weight)).collect();
+        assert!(weights.contains(&(100, Some(60))) && weights.iter().all(|(node_id, weight)| *node_id == 100 || weight.is_none()));
+
+        store.delete_state("hc-1", "node-a", 100, false).await.unwrap();
+        assert_eq!(store.get_state("hc-1", "node-a", false).await.unwrap().len(), 1);
+        assert_eq!(store.get_cluster_states("cluster", false).await.unwrap().len(), 2);
+        assert_eq!(store.get_cluster_states("cluster", true).await.unwrap().len(), 1);
+    }
+
+    pub async fn inventory_lookup(store: &dyn StateStore) {
+        store.migrate().await.unwrap();
+        for (id, port) in [(10, 80), (11, 443)] {
+            let config = serde_json::from_value(json!({ "id": id, "nodebalancer_id": 1, "port": port, "nodes_status": { "up": 1, "down": 0 } })).unwrap();
+            store.update_db_config(config).await.unwrap();
+        }
+        for (id, address, config_id) in [(100, "192.168.1.5:30080", 10), (101, "192.168.1.50:30080", 10), (102, "192.168.1.5:30443", 11), (103, "[fd00::5]:30080", 10)] {
+            let node = serde_json::from_value(json!({ "id": id, "address": address, "config_id": config_id, "nodebalancer_id": 1 })).unwrap();
+            store.update_db_node(node).await.unwrap();
+        }
+
+        let nb_nodes = store.get_by_node_ip_nbcfg("192.168.1.5".parse().unwrap(), 80, &NbScope::default()).await.unwrap();
+        assert_eq!(nb_nodes.len(), 1);
+        assert_eq!((nb_nodes[0].node_id, nb_nodes[0].config_id, nb_nodes[0].address.as_str()), (100, 10, "192.168.1.5:30080"));
+        let nb_nodes = store.get_by_node_ip_nbcfg("fd00::5".parse().unwrap(), 80, &NbScope::default()).await.unwrap();
+        assert_eq!(nb_nodes.iter().map(|n| n.node_id).collect::<Vec<_>>(), vec![103]);
+        let scope = NbScope { nodebalancer_ids: None, config_ids: Some(vec![11]) };
+        assert!(store.get_by_node_ip_nbcfg("192.168.1.5".parse().unwrap(), 80, &scope).await.unwrap().is_empty());
+    }
+
+    pub async fn inventory_prune(store: &dyn StateStore) {
+        store.migrate().await.unwrap();
+        for nb_id in [1, 2] {
+            let nb = LocalNodeBalancerListObject { nb_id, ipv4: format!("203.0.113.{}", nb_id), region: "us-east".to_string(), lke_id: 0 };
+            store.update_db_nb(nb).await.unwrap();
+        }
+        for (id, nodebalancer_id) in [(10, 1), (20, 2)] {
+            let config = serde_json::from_value(json!({ "id": id, "nodebalancer_id": nodebalancer_id, "port": 80, "nodes_status": { "up": 1, "down": 0 } })).unwrap();
+            store.update_db_config(config).await.unwrap();
+        }
+        for (id, config_id, nodebalancer_id) in [(100, 10, 1), (101, 10, 1), (200, 20, 2)] {
+            let node = serde_json::from_value(json!({ "id": id, "address": format!("192.168.1.{}:30080", id), "config_id": config_id, "nodebalancer_id": nodebalancer_id })).unwrap();
+            store.update_db_node(node).await.unwrap();
+        }
+
+        store.prune_inventory(&[1], &[10], &[100]).await.unwrap();
+        assert_eq!(store.get_nodebalancer_by_ip("203.0.113.1").await.unwrap(), Some(1));
+        assert_eq!(store.get_nodebalancer_by_ip("203.0.113.2").await.unwrap(), None);
+        for (ip, count) in [("192.168.1.100", 1), ("192.168.1.101", 0), ("192.168.1.200", 0)] {
+            assert_eq!(store.get_by_node_ip_nbcfg(ip.parse().unwrap(), 80, &NbScope::default()).await.unwrap().len(), count, "{}", ip);
+        }
+    }
+
+    pub async fn audit_newest_first(store: &dyn StateStore) {
+        store.migrate().await.unwrap();
+        for (minute, node_name) in [(0, "node-a"), (1, "node-b"), (2, "node-a")] {
+            let record = AuditRecord {
+                changed_at: DateTime::from_timestamp(1_700_000_000 + minute * 60, 0).unwrap(),
+                cluster_name: "cluster".to_string(),
+                node_name: node_name.to_string(),
+                podip: "10.2.0.5".to_string(),
+                nodebalancer_id: 1,
+                nodebalancer_config_id: 10,
+                node_id: 100,
+                old_mode: "accept".to_string(),
+                new_mode: "drain".to_string(),
+                healthcheck: "web".to_string(),
+                reason: "ProbeFailed".to_string(),
+                probe_type: "tcp".to_string(),
+                error: None,
+                latency_ms: Some(minute as f64),
+                api_success: true,
+            };
+            store.record_audit(&record).await.unwrap();
+        }
+        let query = AuditQuery { node_name: Some("node-a".to_string()), limit: 10, ..Default::default() };
+        let records = store.get_audit(&query).await.unwrap();
+        assert_eq!(records.iter().map(|r| r.latency_ms).collect::<Vec<_>>(), vec![Some(2.0), Some(0.0)]);
+        let query = AuditQuery { limit: 1, ..Default::default() };
+        assert_eq!(store.get_audit(&query).await.unwrap()[0].node_name, "node-a");
+    }
+}
@@ -0,0 +1,401 @@
+use async_trait::async_trait;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::{DeleteParams, ListParams, ObjectMeta, PostParams, Preconditions};
+use kube::{Api, Client};
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::env;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
+use std::time::Duration;
+use crate::database::{LocalNodeBalancerListObject, NodeBalancerConfigObject, NodeObject};
+use super::{apply_update, AuditQuery, AuditRecord, MemoryStore, NbNode, NbScope, StateRow, StateStore, StateUpdate, StoreError};
+
+// Writes that lose a resourceVersion race are retried this many times.
+const MAX_CONFLICT_RETRIES: u32 = 5;
+// First delay before retrying a conflicting write, doubled on every retry.
+const CONFLICT_BACKOFF: Duration = Duration::from_millis(25);
+// Oldest audit entries are dropped past this to stay under the ConfigMap size limit.
+const MAX_AUDIT_RECORDS: usize = 1000;
+// Labels that find the state ConfigMaps of one store.
+const STORE_LABEL: &str = "hc.example.com/state-store";
+const KIND_LABEL: &str = "hc.example.com/state-kind";
+
+// Keeps the state table in ConfigMaps, one per HealthCheck with one JSON
+// entry per (healthcheck, node_name, node_id), so no external database is
+// needed and HealthChecks don't contend for a single object. Updates use
+// the ConfigMap's resourceVersion for optimistic concurrency and back off
+// on conflicts. The NodeBalancer inventory is kept in memory and filled in
+// by the inventory sync.
+pub struct KubernetesStore {
+    api: Api<ConfigMap>,
+    name: String,
+    inventory: MemoryStore,
+}
+
+impl KubernetesStore {
+    pub fn new(client: Client) -> KubernetesStore {
+        let namespace = env::var("STATE_NAMESPACE").unwrap_or("default".to_string());
+        let name = env::var("STATE_CONFIGMAP").unwrap_or("hc-operator-state".to_string());
+        KubernetesStore {
+            api: Api::namespaced(client, &namespace),
+            name,
+            inventory: MemoryStore::default(),
+        }
+    }
+
+    fn audit_name(&self) -> String {
+        format!("{}-audit", self.name)
+    }
+
+    fn shard_labels(&self, shadow: bool) -> BTreeMap<String, String> {
+        BTreeMap::from([(STORE_LABEL.to_string(), self.name.clone()), (KIND_LABEL.to_string(), kind_label(shadow).to_string())])
+    }
+
+    // Rows of one HealthCheck's shard, or of every shard without one.
+    async fn select<F>(&self, shadow: bool, healthcheck: Option<&str>, filter: F) -> Result<Vec<StateRow>, StoreError>
+    where
+        F: Fn(&StateRow) -> bool,
+    {
+        let configmaps = match healthcheck {
+            Some(healthcheck) => self.api.get_opt(&shard_name(&self.name, shadow, healthcheck)).await?.into_iter().collect(),
+            None => {
+                let selector = format!("{}={},{}={}", STORE_LABEL, self.name, KIND_LABEL, kind_label(shadow));
+                self.api.list(&ListParams::default().labels(&selector)).await?.items
+            }
+        };
+        let mut rows = Vec::new();
+        for configmap in configmaps {
+            for value in configmap.data.unwrap_or_default().values() {
+                let row: StateRow = serde_json::from_str(value)?;
+                if filter(&row) {
+                    rows.push(row);
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    async fn modify_shard<F>(&self, shadow: bool, healthcheck: &str, change: F) -> Result<(), StoreError>
+    where
+        F: Fn(&mut BTreeMap<String, String>) -> Result<(), StoreError> + Send + Sync,
+    {
+        let name = shard_name(&self.name, shadow, healthcheck);
+        let labels = self.shard_labels(shadow);
+        retry_on_conflict(|| self.try_modify(&name, &labels, &change)).await
+    }
+
+    // Read, change and write back a ConfigMap. A missing one is created and
+    // one left empty is deleted. Losing a race comes back as a 409.
+    async fn try_modify<F>(&self, name: &str, labels: &BTreeMap<String, String>, change: &F) -> Result<(), StoreError>
+    where
+        F: Fn(&mut BTreeMap<String, String>) -> Result<(), StoreError> + Send + Sync,
+    {
+        let existing = self.api.get_opt(name).await?;
+        let mut data = existing.as_ref().and_then(|cm| cm.data.clone()).unwrap_or_default();
+        change(&mut data)?;
+        match existing {
+            None if data.is_empty() => (),
+            None => {
+                let configmap = ConfigMap {
+                    metadata: ObjectMeta {
+                        name: Some(name.to_string()),
+                        labels: Some(labels.clone()),
+                        ..Default::default()
+                    },
+                    data: Some(data),
+                    ..Default::default()
+                };
+                self.api.create(&PostParams::default(), &configmap).await?;
+            }
+            Some(configmap) if data.is_empty() => {
+                let params = DeleteParams {
+                    preconditions: Some(Preconditions { resource_version: configmap.metadata.resource_version, uid: None }),
+                    ..Default::default()
+                };
+                match self.api.delete(name, &params).await {
+                    Ok(_) => (),
+                    Err(kube::Error::Api(e)) if e.code == 404 => (),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Some(mut configmap) => {
+                configmap.data = Some(data);
+                self.api.replace(name, &PostParams::default(), &configmap).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn kind_label(shadow: bool) -> &'static str {
+    if shadow { "shadow" } else { "state" }
+}
+
+// ConfigMap holding one HealthCheck's rows. Names only allow lowercase
+// alphanumerics, '-' and '.'.
+fn shard_name(name: &str, shadow: bool, healthcheck: &str) -> String {
+    let name = if shadow { format!("{}-shadow-{}", name, healthcheck) } else { format!("{}-{}", name, healthcheck) };
+    name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '-' })
+        .take(253)
+        .collect()
+}
+
+// ConfigMap keys only allow alphanumerics, '-', '_' and '.'.
+fn state_key(healthcheck: &str, node_name: &str, node_id: i32) -> String {
+    format!("{}_{}_{}", healthcheck, node_name, node_id)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '-' })
+        .collect()
+}
+
+// Delay before retry number attempt, counting from 0. Up to half of it is
+// taken off at random so writers that collided don't collide again.
+fn backoff_delay(attempt: u32, random: u64) -> Duration {
+    let delay = CONFLICT_BACKOFF * 2u32.pow(attempt);
+    delay - delay.mul_f64((random % 1000) as f64 / 2000.0)
+}
+
+fn is_conflict(error: &StoreError) -> bool {
+    matches!(error, StoreError::Kube(kube::Error::Api(e)) if e.code == 409)
+}
+
+async fn retry_on_conflict<F, Fut>(mut write: F) -> Result<(), StoreError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), StoreError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match write().await {
+            Err(e) if is_conflict(&e) && attempt < MAX_CONFLICT_RETRIES => {
+                let random = RandomState::new().build_hasher().finish();
+                tokio::time::sleep(backoff_delay(attempt, random)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[async_trait]
+impl StateStore for KubernetesStore {
+    async fn migrate(&self) -> Result<(), StoreError> {
+        let audit = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(self.audit_name()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        if self.api.get_opt(&self.audit_name()).await?.is_none() {
+            match self.api.create(&PostParams::default(), &audit).await {
+                Ok(_) => (),
+                Err(kube::Error::Api(e)) if e.code == 409 => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // State used to be kept in a single ConfigMap per kind. Its entries
+        // are moved to their HealthCheck's shard; those from before state was
+        // kept per HealthCheck can't be mapped to one and are dropped.
+        for shadow in [false, true] {
+            let legacy = if shadow { format!("{}-shadow", self.name) } else { self.name.clone() };
+            let Some(configmap) = self.api.get_opt(&legacy).await? else { continue };
+            if configmap.metadata.labels.as_ref().is_some_and(|labels| labels.contains_key(STORE_LABEL)) {
+                continue;
+            }
+            let mut shards: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+            for (key, value) in configmap.data.unwrap_or_default() {
+                match serde_json::from_str::<StateRow>(&value) {
+                    Ok(row) if !row.healthcheck.is_empty() => shards.entry(row.healthcheck).or_default().push((key, value)),
+                    _ => (),
+                }
+            }
+            for (healthcheck, entries) in shards {
+                self.modify_shard(shadow, &healthcheck, |data| {
+                    for (key, value) in &entries {
+                        data.entry(key.clone()).or_insert(value.clone());
+                    }
+                    Ok(())
+                })
+                .await?;
+            }
+            match self.api.delete(&legacy, &DeleteParams::default()).await {
+                Ok(_) => (),
+                Err(kube::Error::Api(e)) if e.code == 404 => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_state(&self, healthcheck: &str, node_name: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError> {
+        self.select(shadow, Some(healthcheck), |row| row.node_name == node_name).await
+    }
+
+    async fn get_nb_node_states(&self, node_id: i32, clustername: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError> {
+        self.select(shadow, None, |row| row.node_id == node_id && row.cluster_name == clustername).await
+    }
+
+    async fn update_state(&self, update: &StateUpdate, shadow: bool) -> Result<(), StoreError> {
+        let key = state_key(&update.healthcheck, &update.node_name, update.node_id);
+        self.modify_shard(shadow, &update.healthcheck, |data| {
+            let existing = match data.get(&key) {
+                Some(value) => Some(serde_json::from_str(value)?),
+                None => None,
+            };
+            let row = apply_update(existing, update);
+            data.insert(key.clone(), serde_json::to_string(&row)?);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_cluster_states(&self, clustername: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError> {
+        self.select(shadow, None, |row| row.cluster_name == clustername).await
+    }
+
+    async fn delete_state(&self, healthcheck: &str, node_name: &str, node_id: i32, shadow: bool) -> Result<(), StoreError> {
+        let key = state_key(healthcheck, node_name, node_id);
+        self.modify_shard(shadow, healthcheck, |data| {
+            data.remove(&key);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_weight_state(&self, healthcheck: &str, node_name: &str, node_id: i32, latency_ms: f64, weight: i32) -> Result<(), StoreError> {
+        let key = state_key(healthcheck, node_name, node_id);
+        self.modify_shard(false, healthcheck, |data| {
+            if let Some(value) = data.get_mut(&key) {
+                let mut row: StateRow = serde_json::from_str(value)?;
+                row.latency_ms = Some(latency_ms);
+                row.weight = Some(weight);
+                *value = serde_json::to_string(&row)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_by_node_ip_nbcfg(&self, ip: IpAddr, port: i32, scope: &NbScope) -> Result<Vec<NbNode>, StoreError> {
+        self.inventory.get_by_node_ip_nbcfg(ip, port, scope).await
+    }
+
+    async fn get_nodebalancer_by_ip(&self, ip: &str) -> Result<Option<i32>, StoreError> {
+        self.inventory.get_nodebalancer_by_ip(ip).await
+    }
+
+    async fn update_db_nb(&self, nodebalancer: LocalNodeBalancerListObject) -> Result<(), StoreError> {
+        self.inventory.update_db_nb(nodebalancer).await
+    }
+
+    async fn update_db_config(&self, nodebalancer_config: NodeBalancerConfigObject) -> Result<(), StoreError> {
+        self.inventory.update_db_config(nodebalancer_config).await
+    }
+
+    async fn update_db_node(&self, node: NodeObject) -> Result<(), StoreError> {
+        self.inventory.update_db_node(node).await
+    }
+
+    async fn prune_inventory(&self, nodebalancer_ids: &[i32], config_ids: &[i32], node_ids: &[i32]) -> Result<(), StoreError> {
+        self.inventory.prune_inventory(nodebalancer_ids, config_ids, node_ids).await
+    }
+
+    // Entries are keyed by timestamp so they sort oldest first.
+    async fn record_audit(&self, record: &AuditRecord) -> Result<(), StoreError> {
+        let value = serde_json::to_string(record)?;
+        let key = format!("{}.{}.{}", record.changed_at.format("%Y%m%dT%H%M%S%.6fZ"), record.nodebalancer_id, record.node_id);
+        let name = self.audit_name();
+        let change = |data: &mut BTreeMap<String, String>| {
+            data.insert(key.clone(), value.clone());
+            while data.len() > MAX_AUDIT_RECORDS {
+                data.pop_first();
+            }
+            Ok(())
+        };
+        let labels = BTreeMap::new();
+        retry_on_conflict(|| self.try_modify(&name, &labels, &change)).await
+    }
+
+    async fn get_audit(&self, query: &AuditQuery) -> Result<Vec<AuditRecord>, StoreError> {
+        let configmap = self.api.get_opt(&self.audit_name()).await?;
+        let data = configmap.and_then(|cm| cm.data).unwrap_or_default();
+        let mut records = Vec::new();
+        for value in data.values().rev() {
+            let record: AuditRecord = serde_json::from_str(value)?;
+            if query.matches(&record) {
+                records.push(record);
+            }
+            if records.len() as i64 >= query.limit {
+                break;
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn api_error(code: u16) -> StoreError {
+        StoreError::Kube(kube::Error::Api(kube::core::ErrorResponse { status: "Failure".to_string(), message: String::new(), reason: String::new(), code }))
+    }
+
+    #[test]
+    fn state_key_is_a_valid_configmap_key() {
+        assert_eq!(state_key("0b1c-uid", "node-a", 100), "0b1c-uid_node-a_100");
+        assert_eq!(state_key("uid", "lke1/pool:a", -1), "uid_lke1-pool-a_-1");
+    }
+
+    #[test]
+    fn shard_name_is_a_valid_configmap_name() {
+        assert_eq!(shard_name("hc-operator-state", false, "0B1C-uid"), "hc-operator-state-0b1c-uid");
+        assert_eq!(shard_name("hc-operator-state", true, "a_b"), "hc-operator-state-shadow-a-b");
+        assert_eq!(shard_name("state", false, &"x".repeat(300)).len(), 253);
+    }
+
+    #[test]
+    fn backoff_delay_doubles_with_jitter() {
+        assert_eq!(backoff_delay(0, 0), CONFLICT_BACKOFF);
+        assert_eq!(backoff_delay(3, 0), CONFLICT_BACKOFF * 8);
+        for random in [1, 499, 999, u64::MAX] {
+            let delay = backoff_delay(2, random);
+            assert!(delay > CONFLICT_BACKOFF * 2 && delay <= CONFLICT_BACKOFF * 4, "{:?}", delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_on_conflict_retries_conflicts_only() {
+        let attempts = AtomicU32::new(0);
+        let result = retry_on_conflict(|| async {
+            if attempts.fetch_add(1, Ordering::SeqCst) < 2 { Err(api_error(409)) } else { Ok(()) }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let attempts = AtomicU32::new(0);
+        let result = retry_on_conflict(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(api_error(409))
+        })
+        .await;
+        assert!(is_conflict(&result.unwrap_err()));
+        assert_eq!(attempts.load(Ordering::SeqCst), MAX_CONFLICT_RETRIES + 1);
+
+        let attempts = AtomicU32::new(0);
+        let result = retry_on_conflict(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(api_error(500))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,157 @@
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use crate::database::{LocalNodeBalancerListObject, NodeBalancerConfigObject, NodeObject};
+use super::{address_prefix, apply_update, AuditQuery, AuditRecord, NbNode, NbScope, StateRow, StateStore, StateUpdate, StoreError};
+
+type StateKey = (String, String, i32);
+
+#[derive(Default)]
+struct MemoryData {
+    state: HashMap<StateKey, StateRow>,
+    shadow_state: HashMap<StateKey, StateRow>,
+    nodebalancers: BTreeMap<i32, LocalNodeBalancerListObject>,
+    configs: BTreeMap<i32, NodeBalancerConfigObject>,
+    nodes: BTreeMap<i32, NodeObject>,
+    audit: Vec<AuditRecord>,
+}
+
+// Process-local store, lost on restart. Meant for tests and trying the
+// operator out without a database.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<MemoryData>,
+}
+
+#[async_trait]
+impl StateStore for MemoryStore {
+    async fn migrate(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn get_state(&self, healthcheck: &str, node_name: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError> {
+        let data = self.data.lock().unwrap();
+        let table = if shadow { &data.shadow_state } else { &data.state };
+        Ok(table.values().filter(|row| row.healthcheck == healthcheck && row.node_name == node_name).cloned().collect())
+    }
+
+    async fn get_nb_node_states(&self, node_id: i32, clustername: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError> {
+        let data = self.data.lock().unwrap();
+        let table = if shadow { &data.shadow_state } else { &data.state };
+        Ok(table.values().filter(|row| row.node_id == node_id && row.cluster_name == clustername).cloned().collect())
+    }
+
+    async fn update_state(&self, update: &StateUpdate, shadow: bool) -> Result<(), StoreError> {
+        let mut data = self.data.lock().unwrap();
+        let table = if shadow { &mut data.shadow_state } else { &mut data.state };
+        let key = (update.healthcheck.clone(), update.node_name.clone(), update.node_id);
+        let row = apply_update(table.remove(&key), update);
+        table.insert(key, row);
+        Ok(())
+    }
+
+    async fn get_cluster_states(&self, clustername: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError> {
+        let data = self.data.lock().unwrap();
+        let table = if shadow { &data.shadow_state } else { &data.state };
+        Ok(table.values().filter(|row| row.cluster_name == clustername).cloned().collect())
+    }
+
+    async fn delete_state(&self, healthcheck: &str, node_name: &str, node_id: i32, shadow: bool) -> Result<(), StoreError> {
+        let mut data = self.data.lock().unwrap();
+        let table = if shadow { &mut data.shadow_state } else { &mut data.state };
+        table.remove(&(healthcheck.to_string(), node_name.to_string(), node_id));
+        Ok(())
+    }
+
+    async fn update_weight_state(&self, healthcheck: &str, node_name: &str, node_id: i32, latency_ms: f64, weight: i32) -> Result<(), StoreError> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(row) = data.state.get_mut(&(healthcheck.to_string(), node_name.to_string(), node_id)) {
+            row.latency_ms = Some(latency_ms);
+            row.weight = Some(weight);
+        }
+        Ok(())
+    }
+
+    async fn get_by_node_ip_nbcfg(&self, ip: IpAddr, port: i32, scope: &NbScope) -> Result<Vec<NbNode>, StoreError> {
+        let data = self.data.lock().unwrap();
+        let prefix = address_prefix(ip);
+        Ok(data
+            .nodes
+            .values()
+            .filter(|node| node.address.starts_with(&prefix))
+            .filter(|node| data.configs.get(&node.config_id).is_some_and(|cfg| cfg.port == port))
+            .map(|node| NbNode {
+                node_id: node.id,
+                config_id: node.config_id,
+                nodebalancer_id: node.nodebalancer_id,
+                address: node.address.clone(),
+            })
+            .filter(|nb_node| scope.matches(nb_node))
+            .collect())
+    }
+
+    async fn get_nodebalancer_by_ip(&self, ip: &str) -> Result<Option<i32>, StoreError> {
+        let data = self.data.lock().unwrap();
+        Ok(data.nodebalancers.values().find(|nb| nb.ipv4 == ip).map(|nb| nb.nb_id))
+    }
+
+    async fn update_db_nb(&self, nodebalancer: LocalNodeBalancerListObject) -> Result<(), StoreError> {
+        self.data.lock().unwrap().nodebalancers.insert(nodebalancer.nb_id, nodebalancer);
+        Ok(())
+    }
+
+    async fn update_db_config(&self, nodebalancer_config: NodeBalancerConfigObject) -> Result<(), StoreError> {
+        self.data.lock().unwrap().configs.insert(nodebalancer_config.id, nodebalancer_config);
+        Ok(())
+    }
+
+    async fn update_db_node(&self, node: NodeObject) -> Result<(), StoreError> {
+        self.data.lock().unwrap().nodes.insert(node.id, node);
+        Ok(())
+    }
+
+    async fn prune_inventory(&self, nodebalancer_ids: &[i32], config_ids: &[i32], node_ids: &[i32]) -> Result<(), StoreError> {
+        let mut data = self.data.lock().unwrap();
+        data.nodebalancers.retain(|id, _| nodebalancer_ids.contains(id));
+        data.configs.retain(|id, _| config_ids.contains(id));
+        data.nodes.retain(|id, _| node_ids.contains(id));
+        Ok(())
+    }
+
+    async fn record_audit(&self, record: &AuditRecord) -> Result<(), StoreError> {
+        self.data.lock().unwrap().audit.push(record.clone());
+        Ok(())
+    }
+
+    async fn get_audit(&self, query: &AuditQuery) -> Result<Vec<AuditRecord>, StoreError> {
+        let data = self.data.lock().unwrap();
+        Ok(data.audit.iter().rev().filter(|r| query.matches(r)).take(query.limit as usize).cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests;
+
+    #[tokio::test]
+    async fn state_round_trip() {
+        tests::state_round_trip(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn inventory_lookup() {
+        tests::inventory_lookup(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn inventory_prune() {
+        tests::inventory_prune(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn audit_newest_first() {
+        tests::audit_newest_first(&MemoryStore::default()).await;
+    }
+}
@@ -0,0 +1,137 @@
+use async_trait::async_trait;
+use std::net::IpAddr;
+use tokio_postgres::Row;
+use crate::database;
+use crate::database::{LocalNodeBalancerListObject, NodeBalancerConfigObject, NodeObject};
+use super::{address_prefix, AuditQuery, AuditRecord, NbNode, NbScope, StateRow, StateStore, StateUpdate, StoreError};
+
+// The shared Postgres database reached through create_localdb_client.
+pub struct PostgresStore;
+
+fn state_from_row(row: &Row) -> StateRow {
+    StateRow {
+        healthcheck: row.get("healthcheck"),
+        node_name: row.get("node_name"),
+        nodebalancer_id: row.get("nodebalancer_id"),
+        nodebalancer_config_id: row.get("nodebalancer_config_id"),
+        node_id: row.get("node_id"),
+        podip: row.get("podip"),
+        pod_uid: row.get("pod_uid"),
+        port: row.get("port"),
+        lastmode: row.get("lastmode"),
+        current: row.get("current"),
+        cluster_name: row.get("cluster_name"),
+        drain_started: row.get("drain_started"),
+        latency_ms: row.get("latency_ms"),
+        weight: row.get("weight"),
+    }
+}
+
+fn boxed(e: Box<dyn std::error::Error>) -> StoreError {
+    StoreError::Other(e.to_string())
+}
+
+#[async_trait]
+impl StateStore for PostgresStore {
+    async fn migrate(&self) -> Result<(), StoreError> {
+        Ok(database::migrate().await?)
+    }
+
+    async fn get_state(&self, healthcheck: &str, node_name: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError> {
+        let rows = if shadow {
+            database::get_shadow_state(healthcheck, node_name).await?
+        } else {
+            database::get_db_state(healthcheck, node_name).await?
+        };
+        Ok(rows.iter().map(state_from_row).collect())
+    }
+
+    async fn get_nb_node_states(&self, node_id: i32, clustername: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError> {
+        let rows = database::get_nb_node_states(node_id, clustername, shadow).await?;
+        Ok(rows.iter().map(state_from_row).collect())
+    }
+
+    async fn update_state(&self, update: &StateUpdate, shadow: bool) -> Result<(), StoreError> {
+        if shadow {
+            database::update_shadow_state(update).await?;
+        } else {
+            database::update_state(update).await?;
+        }
+        Ok(())
+    }
+
+    async fn get_cluster_states(&self, clustername: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError> {
+        let rows = database::get_cluster_states(&clustername.to_string(), shadow).await?;
+        Ok(rows.iter().map(state_from_row).collect())
+    }
+
+    async fn delete_state(&self, healthcheck: &str, node_name: &str, node_id: i32, shadow: bool) -> Result<(), StoreError> {
+        Ok(database::delete_state(healthcheck, node_name, node_id, shadow).await?)
+    }
+
+    async fn update_weight_state(&self, healthcheck: &str, node_name: &str, node_id: i32, latency_ms: f64, weight: i32) -> Result<(), StoreError> {
+        Ok(database::update_weight_state(healthcheck, node_name, node_id, latency_ms, weight).await?)
+    }
+
+    async fn get_by_node_ip_nbcfg(&self, ip: IpAddr, port: i32, scope: &NbScope) -> Result<Vec<NbNode>, StoreError> {
+        let rows = database::get_by_node_ip_nbcfg(&address_prefix(ip), &port, &scope.nodebalancer_ids, &scope.config_ids).await?;
+        Ok(rows
+            .iter()
+            .map(|row| NbNode {
+                node_id: row.get(0),
+                config_id: row.get(3),
+                nodebalancer_id: row.get(4),
+                address: row.get(1),
+            })
+            .collect())
+    }
+
+    async fn get_nodebalancer_by_ip(&self, ip: &str) -> Result<Option<i32>, StoreError> {
+        let rows = database::get_nb_by_ip(ip).await?;
+        Ok(rows.first().map(|row| row.get("nb_id")))
+    }
+
+    async fn update_db_nb(&self, nodebalancer: LocalNodeBalancerListObject) -> Result<(), StoreError> {
+        database::update_db_nb(nodebalancer).await.map_err(boxed)
+    }
+
+    async fn update_db_config(&self, nodebalancer_config: NodeBalancerConfigObject) -> Result<(), StoreError> {
+        database::update_db_config(nodebalancer_config).await.map_err(boxed)
+    }
+
+    async fn update_db_node(&self, node: NodeObject) -> Result<(), StoreError> {
+        database::update_db_node(node).await.map_err(boxed)
+    }
+
+    async fn prune_inventory(&self, nodebalancer_ids: &[i32], config_ids: &[i32], node_ids: &[i32]) -> Result<(), StoreError> {
+        Ok(database::prune_inventory(nodebalancer_ids, config_ids, node_ids).await?)
+    }
+
+    async fn record_audit(&self, record: &AuditRecord) -> Result<(), StoreError> {
+        Ok(database::insert_audit(record).await?)
+    }
+
+    async fn get_audit(&self, query: &AuditQuery) -> Result<Vec<AuditRecord>, StoreError> {
+        let rows = database::get_audit(query).await?;
+        Ok(rows
+            .iter()
+            .map(|row| AuditRecord {
+                changed_at: row.get("changed_at"),
+                cluster_name: row.get("cluster_name"),
+                node_name: row.get("node_name"),
+                podip: row.get("podip"),
+                nodebalancer_id: row.get("nodebalancer_id"),
+                nodebalancer_config_id: row.get("nodebalancer_config_id"),
+                node_id: row.get("node_id"),
+                old_mode: row.get("old_mode"),
+                new_mode: row.get("new_mode"),
+                healthcheck: row.get("healthcheck"),
+                reason: row.get("reason"),
+                probe_type: row.get("probe_type"),
+                error: row.get("error"),
+                latency_ms: row.get("latency_ms"),
+                api_success: row.get("api_success"),
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,379 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use crate::database::{LocalNodeBalancerListObject, NodeBalancerConfigObject, NodeObject};
+use super::{address_prefix, AuditQuery, AuditRecord, NbNode, NbScope, StateRow, StateStore, StateUpdate, StoreError};
+
+// Embedded single-file store for deployments without Postgres. rusqlite
+// blocks, so every query runs on tokio's blocking thread pool.
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<SqliteStore, StoreError> {
+        let connection = Connection::open(path)?;
+        Ok(SqliteStore { connection: Arc::new(Mutex::new(connection)) })
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, StoreError> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || f(&connection.lock().unwrap()))
+            .await
+            .map_err(|e| StoreError::Other(format!("SQLite query did not complete: {}", e)))?
+    }
+}
+
+const STATE_COLUMNS: &str = "healthcheck, node_name, nodebalancer_id, nodebalancer_config_id, node_id, podip, pod_uid, port, lastmode, current, cluster_name, drain_started, latency_ms, weight";
+
+fn state_from_row(row: &rusqlite::Row) -> rusqlite::Result<StateRow> {
+    Ok(StateRow {
+        healthcheck: row.get(0)?,
+        node_name: row.get(1)?,
+        nodebalancer_id: row.get(2)?,
+        nodebalancer_config_id: row.get(3)?,
+        node_id: row.get(4)?,
+        podip: row.get(5)?,
+        pod_uid: row.get(6)?,
+        port: row.get(7)?,
+        lastmode: row.get(8)?,
+        current: row.get(9)?,
+        cluster_name: row.get(10)?,
+        drain_started: row.get(11)?,
+        latency_ms: row.get(12)?,
+        weight: row.get(13)?,
+    })
+}
+
+fn has_column(connection: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut statement = connection.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = statement.query_map([], |row| row.get::<_, String>(1))?;
+    for name in names {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn state_table(shadow: bool) -> &'static str {
+    if shadow { "shadow_state" } else { "state" }
+}
+
+#[async_trait]
+impl StateStore for SqliteStore {
+    async fn migrate(&self) -> Result<(), StoreError> {
+        self.with_connection(move |connection| {
+            let mut schema = String::new();
+            for table in ["state", "shadow_state"] {
+                // Rows keyed by (port, podip, cluster_name) can't be mapped to a
+                // HealthCheck and node, so the old table is dropped and rebuilt.
+                if !has_column(connection, table, "healthcheck")? {
+                    schema.push_str(&format!("DROP TABLE IF EXISTS {};", table));
+                }
+                schema.push_str(&format!(
+                    "CREATE TABLE IF NOT EXISTS {} (
+                        healthcheck TEXT NOT NULL,
+                        node_name TEXT NOT NULL,
+                        nodebalancer_id INTEGER NOT NULL,
+                        nodebalancer_config_id INTEGER NOT NULL,
+                        node_id INTEGER NOT NULL,
+                        podip TEXT NOT NULL,
+                        pod_uid TEXT NOT NULL DEFAULT '',
+                        port INTEGER NOT NULL,
+                        lastmode TEXT NOT NULL,
+                        current TEXT NOT NULL,
+                        cluster_name TEXT NOT NULL,
+                        drain_started INTEGER,
+                        latency_ms REAL,
+                        weight INTEGER,
+                        PRIMARY KEY (healthcheck, node_name, node_id)
+                    );",
+                    table
+                ));
+            }
+            connection.execute_batch(&schema)?;
+            schema.clear();
+            for table in ["state", "shadow_state"] {
+                if !has_column(connection, table, "pod_uid")? {
+                    schema.push_str(&format!("ALTER TABLE {} ADD COLUMN pod_uid TEXT NOT NULL DEFAULT '';", table));
+                }
+            }
+            schema.push_str(
+                "CREATE TABLE IF NOT EXISTS nodebalancer (nb_id INTEGER PRIMARY KEY, ipv4 TEXT, region TEXT, lke_id INTEGER);
+                 CREATE TABLE IF NOT EXISTS nodebalancer_config (id INTEGER PRIMARY KEY, algorithm TEXT, port INTEGER, up INTEGER, down INTEGER, nodebalancer_id INTEGER);
+                 CREATE TABLE IF NOT EXISTS node (id INTEGER PRIMARY KEY, address TEXT, status TEXT, config_id INTEGER, nodebalancer_id INTEGER);
+                 CREATE TABLE IF NOT EXISTS mode_change_audit (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    changed_at TEXT NOT NULL,
+                    cluster_name TEXT NOT NULL,
+                    node_name TEXT NOT NULL,
+                    podip TEXT NOT NULL,
+                    nodebalancer_id INTEGER NOT NULL,
+                    nodebalancer_config_id INTEGER NOT NULL,
+                    node_id INTEGER NOT NULL,
+                    old_mode TEXT NOT NULL,
+                    new_mode TEXT NOT NULL,
+                    healthcheck TEXT NOT NULL,
+                    reason TEXT NOT NULL,
+                    probe_type TEXT NOT NULL,
+                    error TEXT,
+                    latency_ms REAL,
+                    api_success INTEGER NOT NULL
+                 );",
+            );
+            connection.execute_batch(&schema)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_state(&self, healthcheck: &str, node_name: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError> {
+        let healthcheck = healthcheck.to_string();
+        let node_name = node_name.to_string();
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(&format!("SELECT {} FROM {} WHERE healthcheck = ?1 AND node_name = ?2", STATE_COLUMNS, state_table(shadow)))?;
+            let rows = statement.query_map(params![healthcheck, node_name], state_from_row)?;
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+    }
+
+    async fn get_nb_node_states(&self, node_id: i32, clustername: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError> {
+        let clustername = clustername.to_string();
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(&format!("SELECT {} FROM {} WHERE node_id = ?1 AND cluster_name = ?2", STATE_COLUMNS, state_table(shadow)))?;
+            let rows = statement.query_map(params![node_id, clustername], state_from_row)?;
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+    }
+
+    async fn update_state(&self, update: &StateUpdate, shadow: bool) -> Result<(), StoreError> {
+        let update = update.clone();
+        self.with_connection(move |connection| {
+            let table = state_table(shadow);
+            let query = format!(
+                "INSERT INTO {table} (healthcheck, node_name, nodebalancer_id, nodebalancer_config_id, node_id, podip, pod_uid, port, lastmode, current, cluster_name, drain_started)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, CASE WHEN ?10 = 'drain' THEN strftime('%s', 'now') END)
+                 ON CONFLICT (healthcheck, node_name, node_id) DO UPDATE SET nodebalancer_config_id = excluded.nodebalancer_config_id,
+                 podip = excluded.podip, pod_uid = excluded.pod_uid, port = excluded.port, lastmode = excluded.lastmode, current = excluded.current,
+                 drain_started = CASE WHEN excluded.current = 'drain' THEN COALESCE({table}.drain_started, excluded.drain_started) END"
+            );
+            connection.execute(
+                &query,
+                params![
+                    update.healthcheck,
+                    update.node_name,
+                    update.nodebalancer_id,
+                    update.nodebalancer_config_id,
+                    update.node_id,
+                    update.podip,
+                    update.pod_uid,
+                    update.port,
+                    update.lastmode,
+                    update.current,
+                    update.cluster_name
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_cluster_states(&self, clustername: &str, shadow: bool) -> Result<Vec<StateRow>, StoreError> {
+        let clustername = clustername.to_string();
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(&format!("SELECT {} FROM {} WHERE cluster_name = ?1", STATE_COLUMNS, state_table(shadow)))?;
+            let rows = statement.query_map(params![clustername], state_from_row)?;
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+    }
+
+    async fn delete_state(&self, healthcheck: &str, node_name: &str, node_id: i32, shadow: bool) -> Result<(), StoreError> {
+        let healthcheck = healthcheck.to_string();
+        let node_name = node_name.to_string();
+        self.with_connection(move |connection| {
+            connection.execute(
+                &format!("DELETE FROM {} WHERE healthcheck = ?1 AND node_name = ?2 AND node_id = ?3", state_table(shadow)),
+                params![healthcheck, node_name, node_id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_weight_state(&self, healthcheck: &str, node_name: &str, node_id: i32, latency_ms: f64, weight: i32) -> Result<(), StoreError> {
+        let healthcheck = healthcheck.to_string();
+        let node_name = node_name.to_string();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE state SET latency_ms = ?1, weight = ?2 WHERE healthcheck = ?3 AND node_name = ?4 AND node_id = ?5",
+                params![latency_ms, weight, healthcheck, node_name, node_id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_by_node_ip_nbcfg(&self, ip: IpAddr, port: i32, scope: &NbScope) -> Result<Vec<NbNode>, StoreError> {
+        let scope = scope.clone();
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT node.id, node.config_id, node.nodebalancer_id, node.address FROM node INNER JOIN nodebalancer_config ON node.config_id = nodebalancer_config.id WHERE node.address LIKE ?1 || '%' AND nodebalancer_config.port = ?2",
+            )?;
+            let rows = statement.query_map(params![address_prefix(ip), port], |row| {
+                Ok(NbNode {
+                    node_id: row.get(0)?,
+                    config_id: row.get(1)?,
+                    nodebalancer_id: row.get(2)?,
+                    address: row.get(3)?,
+                })
+            })?;
+            let mut nb_nodes = rows.collect::<Result<Vec<_>, _>>()?;
+            nb_nodes.retain(|nb_node| scope.matches(nb_node));
+            Ok(nb_nodes)
+        })
+        .await
+    }
+
+    async fn get_nodebalancer_by_ip(&self, ip: &str) -> Result<Option<i32>, StoreError> {
+        let ip = ip.to_string();
+        self.with_connection(move |connection| {
+            Ok(connection
+                .query_row("SELECT nb_id FROM nodebalancer WHERE ipv4 = ?1", params![ip], |row| row.get(0))
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn update_db_nb(&self, nodebalancer: LocalNodeBalancerListObject) -> Result<(), StoreError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO nodebalancer (nb_id, ipv4, region, lke_id) VALUES (?1, ?2, ?3, ?4)",
+                params![nodebalancer.nb_id, nodebalancer.ipv4, nodebalancer.region, nodebalancer.lke_id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_db_config(&self, nodebalancer_config: NodeBalancerConfigObject) -> Result<(), StoreError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO nodebalancer_config (id, algorithm, port, up, down, nodebalancer_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![nodebalancer_config.id, nodebalancer_config.algorithm, nodebalancer_config.port, nodebalancer_config.nodes_status.up, nodebalancer_config.nodes_status.down, nodebalancer_config.nodebalancer_id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_db_node(&self, node: NodeObject) -> Result<(), StoreError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO node (id, address, status, config_id, nodebalancer_id) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![node.id, node.address, node.status, node.config_id, node.nodebalancer_id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn prune_inventory(&self, nodebalancer_ids: &[i32], config_ids: &[i32], node_ids: &[i32]) -> Result<(), StoreError> {
+        let tables = [("nodebalancer", "nb_id", nodebalancer_ids.to_vec()), ("nodebalancer_config", "id", config_ids.to_vec()), ("node", "id", node_ids.to_vec())];
+        self.with_connection(move |connection| {
+            let transaction = connection.unchecked_transaction()?;
+            for (table, column, keep) in tables {
+                let ids = transaction.prepare(&format!("SELECT {} FROM {}", column, table))?.query_map([], |row| row.get::<_, i32>(0))?.collect::<Result<Vec<_>, _>>()?;
+                for id in ids.into_iter().filter(|id| !keep.contains(id)) {
+                    transaction.execute(&format!("DELETE FROM {} WHERE {} = ?1", table, column), params![id])?;
+                }
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn record_audit(&self, record: &AuditRecord) -> Result<(), StoreError> {
+        let record = record.clone();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO mode_change_audit (changed_at, cluster_name, node_name, podip, nodebalancer_id, nodebalancer_config_id, node_id, old_mode, new_mode, healthcheck, reason, probe_type, error, latency_ms, api_success)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+                params![
+                    record.changed_at, record.cluster_name, record.node_name, record.podip, record.nodebalancer_id, record.nodebalancer_config_id, record.node_id,
+                    record.old_mode, record.new_mode, record.healthcheck, record.reason, record.probe_type, record.error, record.latency_ms, record.api_success
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_audit(&self, query: &AuditQuery) -> Result<Vec<AuditRecord>, StoreError> {
+        let query = query.clone();
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT changed_at, cluster_name, node_name, podip, nodebalancer_id, nodebalancer_config_id, node_id, old_mode, new_mode, healthcheck, reason, probe_type, error, latency_ms, api_success
+                 FROM mode_change_audit
+                 WHERE (?1 IS NULL OR node_name = ?1) AND (?2 IS NULL OR healthcheck = ?2) AND (?3 IS NULL OR changed_at >= ?3)
+                 ORDER BY id DESC LIMIT ?4",
+            )?;
+            let rows = statement.query_map(params![query.node_name, query.healthcheck, query.since, query.limit], |row| {
+                Ok(AuditRecord {
+                    changed_at: row.get(0)?,
+                    cluster_name: row.get(1)?,
+                    node_name: row.get(2)?,
+                    podip: row.get(3)?,
+                    nodebalancer_id: row.get(4)?,
+                    nodebalancer_config_id: row.get(5)?,
+                    node_id: row.get(6)?,
+                    old_mode: row.get(7)?,
+                    new_mode: row.get(8)?,
+                    healthcheck: row.get(9)?,
+                    reason: row.get(10)?,
+                    probe_type: row.get(11)?,
+                    error: row.get(12)?,
+                    latency_ms: row.get(13)?,
+                    api_success: row.get(14)?,
+                })
+            })?;
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests;
+
+    #[tokio::test]
+    async fn state_round_trip() {
+        tests::state_round_trip(&SqliteStore::open(":memory:").unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn inventory_lookup() {
+        tests::inventory_lookup(&SqliteStore::open(":memory:").unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn inventory_prune() {
+        tests::inventory_prune(&SqliteStore::open(":memory:").unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn audit_newest_first() {
+        tests::audit_newest_first(&SqliteStore::open(":memory:").unwrap()).await;
+    }
+}